@@ -5,13 +5,26 @@ pub mod extension_hub {
     tonic::include_proto!("abi");
 }
 
+response_new!(GetServerInfoResponse, ServerInfo);
 response_new!(CheckTarResponse);
 response_new!(UploadTarResponse, UploadTarData);
+response_new!(UploadTarStreamResponse, UploadTarStreamData);
 response_new!(DownloadTarResponse, DownloadTarData);
-response_new!(ReplaceTextResponse);
+response_new!(ReplaceTextResponse, ReplaceTextData);
+response_new!(UndoReplaceResponse, UndoReplaceData);
 response_new!(UnTarResponse);
-response_new!(ClearDirResponse);
-response_new!(ClearTarDirResponse);
+response_new!(PlanUploadResponse, PlanUploadData);
+response_new!(UploadObjectsResponse, UploadObjectsData);
+response_new!(CommitUploadResponse, Release);
+response_new!(ListReleasesResponse, ListReleasesData);
+response_new!(RollbackResponse, Release);
+response_new!(ListExtensionsResponse, ListExtensionsData);
+response_new!(GetExtensionResponse, Extension);
+response_new!(ListTarsResponse, ListTarsData);
+response_new!(GetTarInfoResponse, TarInfo);
+response_new!(DeleteTarResponse, ClearData);
+response_new!(ClearDirResponse, ClearData);
+response_new!(ClearTarDirResponse, ClearData);
 
 // app_error_to_response!(CheckTarResponse);
 // app_error_to_response!(UploadTarResponse, true);
@@ -1,14 +1,21 @@
-use std::io::BufWriter;
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
 use std::sync::Arc;
 
 use abi::extension_hub_client::ExtensionHubClient;
+use abi::ArchiveFormat;
 use anyhow::{anyhow, Result};
 use clap::Parser;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use extension_hub::error::HubErrorCode;
+use futures::{Stream, StreamExt};
 use reqwest::multipart::Part;
+use walkdir::WalkDir;
+use xz2::write::XzEncoder;
+use zip::write::SimpleFileOptions;
 
 pub mod abi {
     tonic::include_proto!("abi");
@@ -23,30 +30,262 @@ struct Config {
     extension_name: String,
     #[arg(short, long, value_parser, default_value = "./")]
     dir: PathBuf,
+    /// Upload and untar through the `UploadTarStream` rpc instead of http
+    #[arg(long)]
+    stream: bool,
+    /// Bearer token sent with every request
+    #[arg(long)]
+    token: Option<String>,
+    /// Archive format to upload: tar.gz, tar.zst, tar.xz, tar or zip.
+    /// Defaults to the one the server prefers
+    #[arg(long, value_parser = parse_format)]
+    format: Option<ArchiveFormat>,
+    /// Only upload the files the server does not have yet
+    #[arg(long)]
+    delta: bool,
+}
+
+fn parse_format(name: &str) -> Result<ArchiveFormat, String> {
+    match name.trim_start_matches('.') {
+        "tar.gz" | "tgz" => Ok(ArchiveFormat::TarGz),
+        "tar.zst" => Ok(ArchiveFormat::TarZst),
+        "tar.xz" => Ok(ArchiveFormat::TarXz),
+        "tar" => Ok(ArchiveFormat::Tar),
+        "zip" => Ok(ArchiveFormat::Zip),
+        _ => Err(format!("unknown archive format '{}'", name)),
+    }
+}
+
+/// The chunks sending one object to `UploadObjects`. The file is read as the
+/// stream is polled, so only one chunk is held in memory at a time. A read
+/// error ends the object early, the server then refuses it for its hash.
+fn object_chunks(hash: String, path: PathBuf) -> impl Stream<Item = abi::ObjectChunk> {
+    let first = abi::ObjectChunk { hash, data: vec![] };
+    let data = futures::stream::unfold(None, move |file: Option<std::fs::File>| {
+        let path = path.clone();
+        async move {
+            let read = |file: Option<std::fs::File>| -> std::io::Result<_> {
+                let mut file = match file {
+                    Some(file) => file,
+                    None => std::fs::File::open(&path)?,
+                };
+                let mut data = vec![0; 64 * 1024];
+                let len = file.read(&mut data)?;
+                data.truncate(len);
+                Ok((data, file))
+            };
+            match read(file) {
+                Ok((data, _)) if data.is_empty() => None,
+                Ok((data, file)) => Some((
+                    abi::ObjectChunk {
+                        data,
+                        ..Default::default()
+                    },
+                    Some(file),
+                )),
+                Err(e) => {
+                    eprintln!("Failed to read {:?}: {}", path, e);
+                    None
+                }
+            }
+        }
+    });
+    futures::stream::once(async { first }).chain(data)
 }
 
 impl Config {
-    fn dir_to_tar_file(&self) -> Result<(Vec<u8>, String)> {
+    fn request<T>(&self, message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        if let Some(token) = &self.token {
+            if let Ok(value) = format!("Bearer {}", token).parse() {
+                request.metadata_mut().insert("authorization", value);
+            }
+        }
+        request
+    }
+
+    /// The format given on the command line, or the first one the server
+    /// lists. Servers without `GetServerInfo` only take tar.gz.
+    async fn archive_format(
+        &self,
+        client: &mut ExtensionHubClient<tonic::transport::Channel>,
+    ) -> ArchiveFormat {
+        if let Some(format) = self.format {
+            return format;
+        }
+        let request = self.request(abi::GetServerInfoRequest {});
+        match client.get_server_info(request).await {
+            Ok(response) => response
+                .into_inner()
+                .data
+                .and_then(|info| info.archive_formats().next())
+                .unwrap_or(ArchiveFormat::TarGz),
+            Err(_) => ArchiveFormat::TarGz,
+        }
+    }
+
+    fn dir_to_tar_file(&self, format: ArchiveFormat) -> Result<(Vec<u8>, String)> {
         let mut output: Vec<u8> = Vec::new();
-        {
-            let writer = BufWriter::new(&mut output);
-            let enc = GzEncoder::new(writer, Compression::default());
-            let mut tar = tar::Builder::new(enc);
-            tar.append_dir_all("", &self.dir)?;
+        match format {
+            ArchiveFormat::TarGz => {
+                let enc = GzEncoder::new(&mut output, Compression::default());
+                self.append_tar(enc)?.finish()?;
+            }
+            ArchiveFormat::TarZst => {
+                let enc = zstd::Encoder::new(&mut output, 0)?;
+                self.append_tar(enc)?.finish()?;
+            }
+            ArchiveFormat::TarXz => {
+                let enc = XzEncoder::new(&mut output, 6);
+                self.append_tar(enc)?.finish()?;
+            }
+            ArchiveFormat::Tar => {
+                self.append_tar(&mut output)?;
+            }
+            ArchiveFormat::Zip => self.append_zip(Cursor::new(&mut output))?,
         }
         let hash = blake3::hash(&output).to_hex();
         Ok((output, hash.to_string()))
     }
 
+    /// Lists `dir` for `PlanUpload`, following symlinks like `append_tar`.
+    fn dir_manifest(&self) -> Result<Vec<abi::FileEntry>> {
+        let mut files = Vec::new();
+        for entry in WalkDir::new(&self.dir).follow_links(true).min_depth(1) {
+            let entry = entry?;
+            let path = entry
+                .path()
+                .strip_prefix(&self.dir)?
+                .to_string_lossy()
+                .to_string();
+            if entry.file_type().is_dir() {
+                files.push(abi::FileEntry {
+                    path,
+                    kind: abi::FileKind::Dir.into(),
+                    ..Default::default()
+                });
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let mut hasher = blake3::Hasher::new();
+            std::io::copy(&mut std::fs::File::open(entry.path())?, &mut hasher)?;
+            files.push(abi::FileEntry {
+                path,
+                kind: abi::FileKind::File.into(),
+                hash: hasher.finalize().to_hex().to_string(),
+                size: metadata.len(),
+                executable: metadata.permissions().mode() & 0o111 != 0,
+                target: String::new(),
+            });
+        }
+        Ok(files)
+    }
+
+    /// Sends the files the server lacks and deploys the dir from what it
+    /// stores. `false` when the server does not take delta uploads.
+    async fn upload_delta(
+        &self,
+        client: &mut ExtensionHubClient<tonic::transport::Channel>,
+    ) -> Result<bool> {
+        let files = self.dir_manifest()?;
+        let request = self.request(abi::PlanUploadRequest {
+            files: files.clone(),
+        });
+        let plan = match client.plan_upload(request).await {
+            Ok(response) => response
+                .into_inner()
+                .data
+                .ok_or(anyhow!("Upload plan not found"))?,
+            Err(e) if e.code() == tonic::Code::Unimplemented => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let paths: HashMap<&str, PathBuf> = files
+            .iter()
+            .filter(|f| f.kind() == abi::FileKind::File)
+            .map(|f| (f.hash.as_str(), self.dir.join(&f.path)))
+            .collect();
+        let total: u64 = files.iter().map(|f| f.size).sum();
+        println!(
+            "Uploading {} of {} files, {} of {} bytes",
+            plan.missing.len(),
+            paths.len(),
+            plan.missing_bytes,
+            total
+        );
+
+        if !plan.missing.is_empty() {
+            let objects = plan
+                .missing
+                .iter()
+                .map(|hash| {
+                    let path = paths
+                        .get(hash.as_str())
+                        .ok_or(anyhow!("Server asked for unknown file {}", hash))?;
+                    Ok((hash.clone(), path.clone()))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let chunks =
+                futures::stream::iter(objects).flat_map(|(hash, path)| object_chunks(hash, path));
+            client.upload_objects(self.request(chunks)).await?;
+        }
+
+        let request = self.request(abi::CommitUploadRequest {
+            target_dir: self.extension_name.clone(),
+            files,
+            overwrite: Some(true),
+        });
+        let release = client
+            .commit_upload(request)
+            .await?
+            .into_inner()
+            .data
+            .ok_or(anyhow!("Release not found"))?;
+        println!(
+            "Deploy success: release {} of tree {}",
+            release.id, release.tar_hash
+        );
+        Ok(true)
+    }
+
+    fn append_tar<W: Write>(&self, writer: W) -> Result<W> {
+        let mut tar = tar::Builder::new(writer);
+        tar.append_dir_all("", &self.dir)?;
+        Ok(tar.into_inner()?)
+    }
+
+    /// Zips `dir` like `append_tar` tars it, following symlinks.
+    fn append_zip<W: Write + std::io::Seek>(&self, writer: W) -> Result<()> {
+        let mut zip = zip::ZipWriter::new(writer);
+        for entry in WalkDir::new(&self.dir).follow_links(true) {
+            let entry = entry?;
+            let relative = entry.path().strip_prefix(&self.dir)?;
+            if relative.as_os_str().is_empty() {
+                continue;
+            }
+            let name = relative.to_string_lossy();
+            let mode = entry.metadata()?.permissions().mode();
+            let options = SimpleFileOptions::default().unix_permissions(mode);
+            if entry.file_type().is_dir() {
+                zip.add_directory(name, options)?;
+            } else {
+                zip.start_file(name, options)?;
+                std::io::copy(&mut std::fs::File::open(entry.path())?, &mut zip)?;
+            }
+        }
+        zip.finish()?;
+        Ok(())
+    }
+
     async fn upload_tar(
         &self,
         client: &mut ExtensionHubClient<tonic::transport::Channel>,
         bytes: Arc<Vec<u8>>,
         hash: &str,
     ) -> Result<()> {
-        let request = tonic::Request::new(abi::UploadTarRequest {
+        let request = self.request(abi::UploadTarRequest {
             tar_hash: hash.to_owned(),
             un_tar: None,
+            ttl_secs: None,
         });
 
         let file = bytes.as_ref().to_owned();
@@ -59,11 +298,11 @@ impl Config {
         let part = Part::bytes(file);
         let form = reqwest::multipart::Form::new().part("file", part);
 
-        let response = reqwest::Client::new()
-            .post(&url)
-            .multipart(form)
-            .send()
-            .await?;
+        let mut request = reqwest::Client::new().post(&url).multipart(form);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?;
         if response.status().is_success() {
             println!("Upload tar success: {}", &url);
             Ok(())
@@ -71,6 +310,41 @@ impl Config {
             anyhow::bail!("Upload tar failed: {} {:?}", &url, response);
         }
     }
+
+    async fn upload_tar_stream(
+        &self,
+        client: &mut ExtensionHubClient<tonic::transport::Channel>,
+        bytes: Arc<Vec<u8>>,
+        hash: &str,
+    ) -> Result<()> {
+        let mut chunks = vec![abi::UploadChunk {
+            tar_hash: hash.to_owned(),
+            un_tar: Some(abi::UnTarRequest {
+                tar_hash: hash.to_owned(),
+                target_dir: self.extension_name.clone(),
+                overwrite: Some(true),
+            }),
+            data: vec![],
+        }];
+        chunks.extend(bytes.chunks(64 * 1024).map(|data| abi::UploadChunk {
+            data: data.to_vec(),
+            ..Default::default()
+        }));
+
+        let res = client
+            .upload_tar_stream(self.request(futures::stream::iter(chunks)))
+            .await?
+            .into_inner();
+        let data = res.data.ok_or(anyhow!("Upload result not found"))?;
+        println!(
+            "Upload tar success: {} ({} bytes, {:?}) -> {:?}",
+            data.tar_hash,
+            data.size,
+            data.format(),
+            data.target_dir
+        );
+        Ok(())
+    }
 }
 
 #[tokio::main]
@@ -91,7 +365,16 @@ async fn main() -> Result<()> {
     let mut client: ExtensionHubClient<tonic::transport::Channel> =
         ExtensionHubClient::connect(addr).await?;
 
-    let (file, hash) = cli.dir_to_tar_file()?;
+    if cli.delta {
+        if cli.upload_delta(&mut client).await? {
+            return Ok(());
+        }
+        println!("Server does not take delta uploads, uploading the whole dir");
+    }
+
+    let format = cli.archive_format(&mut client).await;
+    println!("Archive format: {:?}", format);
+    let (file, hash) = cli.dir_to_tar_file(format)?;
     let file = Arc::new(file);
     let extension_name: Arc<&str> = Arc::new(&cli.extension_name);
     let hash = Arc::new(hash);
@@ -101,7 +384,7 @@ async fn main() -> Result<()> {
         if timer >= 10 {
             anyhow::bail!("Timeout");
         }
-        let request = tonic::Request::new(abi::CheckTarRequest {
+        let request = cli.request(abi::CheckTarRequest {
             tar_hash: hash.clone().as_ref().to_owned(),
             file_path: extension_name.clone().as_ref().to_owned().to_owned(),
         });
@@ -115,6 +398,11 @@ async fn main() -> Result<()> {
             Err(e) => {
                 let error: HubErrorCode = HubErrorCode::try_from(e.details()).expect("error code");
                 match error {
+                    HubErrorCode::TarNotExist if cli.stream => {
+                        cli.upload_tar_stream(&mut client, file.clone(), &hash)
+                            .await?;
+                        return Ok(());
+                    }
                     HubErrorCode::TarNotExist => {
                         cli.upload_tar(&mut client, file.clone(), &hash).await?;
                     }
@@ -130,7 +418,7 @@ async fn main() -> Result<()> {
         };
         timer += 1;
     }
-    let request = tonic::Request::new(abi::UnTarRequest {
+    let request = cli.request(abi::UnTarRequest {
         tar_hash: hash.clone().as_ref().to_owned(),
         target_dir: extension_name.clone().as_ref().to_owned().to_owned(),
         overwrite: Some(true),
@@ -37,7 +37,49 @@ pub enum HubError {
     ResourceNotFount, // 1009
 
     #[error("Invalid path: {0}")]
-    InvalidPath(String), // 1100
+    InvalidPath(String), // 1010
+
+    #[error("Directory '{0}' is still in use")]
+    DirInUse(String), // 1011
+
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String), // 1012
+
+    #[error("Upload offset does not match, expected: {0}, found: {1}")]
+    OffsetNotMatch(u64, u64), // 1013
+
+    #[error("Archive unpacks to more than {0} bytes")]
+    ArchiveTooLarge(u64), // 1014
+
+    #[error("Archive has more than {0} entries")]
+    TooManyEntries(u64), // 1015
+
+    #[error("Unsafe archive entry '{0}': {1}")]
+    UnsafeEntry(String, String), // 1016
+
+    #[error("Release of '{0}' with tar hash '{1}' not exist")]
+    ReleaseNotExist(String, String), // 1017
+
+    #[error("Tar package with hash '{0}' is still deployed to: {1}")]
+    TarInUse(String, String), // 1018
+
+    #[error("Missing or invalid token")]
+    Unauthenticated, // 1019
+
+    #[error("Token lacks the '{0}' scope")]
+    PermissionDenied(String), // 1020
+
+    #[error("Invalid url: {0}")]
+    InvalidUrl(String), // 1021
+
+    #[error("Replace run '{0}' not exist")]
+    ReplaceRunNotExist(String), // 1022
+
+    #[error("Unsupported archive format, expected tar.gz, tar.zst, tar.xz, tar or zip")]
+    UnsupportedArchive, // 1023
+
+    #[error("Upload is larger than {0} bytes")]
+    UploadTooLarge(u64), // 1024
 
     // detailed errors
     #[error("Unsupported API: {0}")]
@@ -68,6 +110,20 @@ pub enum HubErrorCode {
     HashNotMatch = 1008,
     ResourceNotFount = 1009,
     InvalidPath = 1010,
+    DirInUse = 1011,
+    InvalidArgument = 1012,
+    OffsetNotMatch = 1013,
+    ArchiveTooLarge = 1014,
+    TooManyEntries = 1015,
+    UnsafeEntry = 1016,
+    ReleaseNotExist = 1017,
+    TarInUse = 1018,
+    Unauthenticated = 1019,
+    PermissionDenied = 1020,
+    InvalidUrl = 1021,
+    ReplaceRunNotExist = 1022,
+    UnsupportedArchive = 1023,
+    UploadTooLarge = 1024,
     UnsupportedApi = 1100,
     MalformedApiResponse = 1101,
     UnSupportedErrorCode = 1102,
@@ -89,6 +145,20 @@ impl From<&HubError> for i32 {
             HubError::HashNotMatch(_, _) => 1008_i32,
             HubError::ResourceNotFount => 1009_i32,
             HubError::InvalidPath(_) => 1010_i32,
+            HubError::DirInUse(_) => 1011_i32,
+            HubError::InvalidArgument(_) => 1012_i32,
+            HubError::OffsetNotMatch(_, _) => 1013_i32,
+            HubError::ArchiveTooLarge(_) => 1014_i32,
+            HubError::TooManyEntries(_) => 1015_i32,
+            HubError::UnsafeEntry(_, _) => 1016_i32,
+            HubError::ReleaseNotExist(_, _) => 1017_i32,
+            HubError::TarInUse(_, _) => 1018_i32,
+            HubError::Unauthenticated => 1019_i32,
+            HubError::PermissionDenied(_) => 1020_i32,
+            HubError::InvalidUrl(_) => 1021_i32,
+            HubError::ReplaceRunNotExist(_) => 1022_i32,
+            HubError::UnsupportedArchive => 1023_i32,
+            HubError::UploadTooLarge(_) => 1024_i32,
             HubError::UnsupportedApi(_) => 1100_i32,
             HubError::MalformedApiResponse(_) => 1101_i32,
             HubError::UnSupportedErrorCode => 1102_i32,
@@ -128,6 +198,20 @@ impl TryFrom<&[u8]> for HubErrorCode {
             1008 => Ok(HubErrorCode::HashNotMatch),
             1009 => Ok(HubErrorCode::ResourceNotFount),
             1010 => Ok(HubErrorCode::InvalidPath),
+            1011 => Ok(HubErrorCode::DirInUse),
+            1012 => Ok(HubErrorCode::InvalidArgument),
+            1013 => Ok(HubErrorCode::OffsetNotMatch),
+            1014 => Ok(HubErrorCode::ArchiveTooLarge),
+            1015 => Ok(HubErrorCode::TooManyEntries),
+            1016 => Ok(HubErrorCode::UnsafeEntry),
+            1017 => Ok(HubErrorCode::ReleaseNotExist),
+            1018 => Ok(HubErrorCode::TarInUse),
+            1019 => Ok(HubErrorCode::Unauthenticated),
+            1020 => Ok(HubErrorCode::PermissionDenied),
+            1021 => Ok(HubErrorCode::InvalidUrl),
+            1022 => Ok(HubErrorCode::ReplaceRunNotExist),
+            1023 => Ok(HubErrorCode::UnsupportedArchive),
+            1024 => Ok(HubErrorCode::UploadTooLarge),
             1100 => Ok(HubErrorCode::UnsupportedApi),
             1101 => Ok(HubErrorCode::MalformedApiResponse),
             1102 => Ok(HubErrorCode::UnSupportedErrorCode),
@@ -180,6 +264,48 @@ impl From<HubError> for Status {
             ),
             HubError::ResourceNotFount => Status::not_found("Resource not found"),
             HubError::InvalidPath(path) => Status::invalid_argument(format!("Invalid path: {}", path)),
+            HubError::DirInUse(dir) => {
+                Status::failed_precondition(format!("Directory '{}' is still in use", dir))
+            }
+            HubError::InvalidArgument(msg) => {
+                Status::invalid_argument(format!("Invalid argument: {}", msg))
+            }
+            HubError::OffsetNotMatch(expected, found) => Status::failed_precondition(format!(
+                "Upload offset does not match, expected: {expected}, found: {found}"
+            )),
+            HubError::ArchiveTooLarge(max) => {
+                Status::invalid_argument(format!("Archive unpacks to more than {} bytes", max))
+            }
+            HubError::TooManyEntries(max) => {
+                Status::invalid_argument(format!("Archive has more than {} entries", max))
+            }
+            HubError::UnsafeEntry(path, reason) => {
+                Status::invalid_argument(format!("Unsafe archive entry '{}': {}", path, reason))
+            }
+            HubError::ReleaseNotExist(dir, hash) => Status::not_found(format!(
+                "Release of '{}' with tar hash '{}' not exist",
+                dir, hash
+            )),
+            HubError::TarInUse(hash, dirs) => Status::failed_precondition(format!(
+                "Tar package with hash '{}' is still deployed to: {}",
+                hash, dirs
+            )),
+            HubError::Unauthenticated => Status::unauthenticated("Missing or invalid token"),
+            HubError::PermissionDenied(scope) => {
+                Status::permission_denied(format!("Token lacks the '{}' scope", scope))
+            }
+            HubError::InvalidUrl(reason) => {
+                Status::permission_denied(format!("Invalid url: {}", reason))
+            }
+            HubError::ReplaceRunNotExist(run_id) => {
+                Status::not_found(format!("Replace run '{}' not exist", run_id))
+            }
+            HubError::UnsupportedArchive => Status::invalid_argument(
+                "Unsupported archive format, expected tar.gz, tar.zst, tar.xz, tar or zip",
+            ),
+            HubError::UploadTooLarge(max) => {
+                Status::invalid_argument(format!("Upload is larger than {} bytes", max))
+            }
         };
         let status = Status::with_details(status.code(), status.message(), bytes.into());
         status
@@ -0,0 +1,116 @@
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::Path;
+use tar::Archive;
+use xz2::read::XzDecoder;
+
+use extension_hub::abi::extension_hub as abi;
+use extension_hub::error::HubError;
+
+extern crate extension_hub;
+
+/// Bytes read from an upload to tell its format.
+pub const MAGIC_LEN: usize = 512;
+
+/// How a stored tar is packed, detected from its first bytes when it is
+/// uploaded and kept in its file name, `<hash><suffix>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    /// The only format before others were accepted
+    #[default]
+    TarGz,
+    TarZst,
+    TarXz,
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub const ALL: [ArchiveFormat; 5] = [
+        ArchiveFormat::TarGz,
+        ArchiveFormat::TarZst,
+        ArchiveFormat::TarXz,
+        ArchiveFormat::Tar,
+        ArchiveFormat::Zip,
+    ];
+
+    pub fn suffix(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => ".tar.gz",
+            ArchiveFormat::TarZst => ".tar.zst",
+            ArchiveFormat::TarXz => ".tar.xz",
+            ArchiveFormat::Tar => ".tar",
+            ArchiveFormat::Zip => ".zip",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::TarZst => "application/zstd",
+            ArchiveFormat::TarXz => "application/x-xz",
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+
+    pub fn to_abi(self) -> abi::ArchiveFormat {
+        match self {
+            ArchiveFormat::TarGz => abi::ArchiveFormat::TarGz,
+            ArchiveFormat::TarZst => abi::ArchiveFormat::TarZst,
+            ArchiveFormat::TarXz => abi::ArchiveFormat::TarXz,
+            ArchiveFormat::Tar => abi::ArchiveFormat::Tar,
+            ArchiveFormat::Zip => abi::ArchiveFormat::Zip,
+        }
+    }
+
+    /// Tells the format from the first [`MAGIC_LEN`] bytes of a file.
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Some(ArchiveFormat::TarGz)
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(ArchiveFormat::TarZst)
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(ArchiveFormat::TarXz)
+        } else if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+            Some(ArchiveFormat::Zip)
+        } else if head.get(257..262) == Some(b"ustar".as_slice()) {
+            Some(ArchiveFormat::Tar)
+        } else {
+            None
+        }
+    }
+
+    pub fn detect_file(path: &Path) -> Result<Self, HubError> {
+        let mut head = Vec::with_capacity(MAGIC_LEN);
+        std::fs::File::open(path)?
+            .take(MAGIC_LEN as u64)
+            .read_to_end(&mut head)?;
+        Self::detect(&head).ok_or(HubError::UnsupportedArchive)
+    }
+
+    /// Splits a stored file name into the tar hash and its format.
+    pub fn split_file_name(file_name: &str) -> Option<(&str, Self)> {
+        Self::ALL.iter().find_map(|format| {
+            file_name
+                .strip_suffix(format.suffix())
+                .filter(|hash| !hash.is_empty())
+                .map(|hash| (hash, *format))
+        })
+    }
+
+    /// The tar inside a stored file, `None` for zip.
+    pub fn open_tar(self, path: &Path) -> Result<Option<Archive<Box<dyn Read>>>, HubError> {
+        let file = std::fs::File::open(path)?;
+        let reader: Box<dyn Read> = match self {
+            ArchiveFormat::TarGz => Box::new(GzDecoder::new(file)),
+            ArchiveFormat::TarZst => Box::new(zstd::Decoder::new(file)?),
+            ArchiveFormat::TarXz => Box::new(XzDecoder::new(file)),
+            ArchiveFormat::Tar => Box::new(file),
+            ArchiveFormat::Zip => return Ok(None),
+        };
+        Ok(Some(Archive::new(reader)))
+    }
+}
@@ -0,0 +1,267 @@
+use axum::{
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use clap::Parser;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tonic::Status;
+
+use extension_hub::error::HubError;
+
+extern crate extension_hub;
+
+/// What a token may do, `admin` allows everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// List and download tars, releases and extensions
+    Read,
+    /// Upload tars
+    Upload,
+    /// Untar, replace text and roll back target dirs
+    Deploy,
+    /// Delete tars and dirs
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Scope::Read => "read",
+            Scope::Upload => "upload",
+            Scope::Deploy => "deploy",
+            Scope::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "read" => Ok(Scope::Read),
+            "upload" => Ok(Scope::Upload),
+            "deploy" => Ok(Scope::Deploy),
+            "admin" => Ok(Scope::Admin),
+            other => Err(format!("unknown scope '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthToken {
+    pub token: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// Parses `<token>:<scope>[,<scope>...]`, as given on the command line.
+impl FromStr for AuthToken {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (token, scopes) = s
+            .rsplit_once(':')
+            .ok_or_else(|| "expected <token>:<scope>[,<scope>...]".to_owned())?;
+        if token.is_empty() {
+            return Err("empty token".to_owned());
+        }
+        Ok(AuthToken {
+            token: token.to_owned(),
+            scopes: scopes
+                .split(',')
+                .map(Scope::from_str)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[derive(Parser, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AuthConfig {
+    /// Accepted bearer token as `<token>:<scope>[,<scope>...]`, scopes are
+    /// read, upload, deploy and admin. Without tokens nothing is checked
+    #[arg(long = "auth-token")]
+    #[serde(default)]
+    pub tokens: Vec<AuthToken>,
+    /// Glob of static file paths that need a token with the `read` scope
+    #[arg(long = "protect-path")]
+    #[serde(default)]
+    pub protected_paths: Vec<String>,
+}
+
+/// The scopes of the token a request was made with.
+#[derive(Debug, Clone)]
+pub struct Grant {
+    scopes: Vec<Scope>,
+}
+
+impl Grant {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    pub fn check(&self, scope: Scope) -> Result<(), HubError> {
+        if self.allows(scope) {
+            Ok(())
+        } else {
+            Err(HubError::PermissionDenied(scope.to_string()))
+        }
+    }
+}
+
+/// Checks the `Grant` the interceptor put on a gRPC request.
+pub fn require<T>(request: &tonic::Request<T>, scope: Scope) -> Result<Grant, HubError> {
+    let grant = authenticated(request)?;
+    grant.check(scope)?;
+    Ok(grant)
+}
+
+/// The `Grant` of a gRPC request that needs a valid token but no scope.
+pub fn authenticated<T>(request: &tonic::Request<T>) -> Result<Grant, HubError> {
+    request
+        .extensions()
+        .get::<Grant>()
+        .cloned()
+        .ok_or(HubError::Unauthenticated)
+}
+
+/// Tokens are kept as digests, so comparing them takes the same time
+/// whatever the first mismatching byte is.
+#[derive(Debug)]
+pub struct Authenticator {
+    tokens: Vec<(blake3::Hash, Vec<Scope>)>,
+    protected_paths: GlobSet,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Result<Self, HubError> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &config.protected_paths {
+            let glob = Glob::new(pattern.trim_start_matches('/'))
+                .map_err(|e| HubError::ConfigureError(e.to_string()))?;
+            builder.add(glob);
+        }
+        Ok(Authenticator {
+            tokens: config
+                .tokens
+                .iter()
+                .map(|t| (blake3::hash(t.token.as_bytes()), t.scopes.clone()))
+                .collect(),
+            protected_paths: builder
+                .build()
+                .map_err(|e| HubError::ConfigureError(e.to_string()))?,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Resolves an `Authorization: Bearer <token>` header value.
+    pub fn authenticate(&self, authorization: Option<&str>) -> Result<Grant, HubError> {
+        if !self.is_enabled() {
+            return Ok(Grant {
+                scopes: vec![Scope::Admin],
+            });
+        }
+        let token = authorization
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or(HubError::Unauthenticated)?;
+        let digest = blake3::hash(token.trim().as_bytes());
+        self.tokens
+            .iter()
+            .find(|(hash, _)| *hash == digest)
+            .map(|(_, scopes)| Grant {
+                scopes: scopes.clone(),
+            })
+            .ok_or(HubError::Unauthenticated)
+    }
+
+    fn authorize(&self, headers: &HeaderMap, scope: Scope) -> Result<Grant, HubError> {
+        let authorization = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok());
+        let grant = self.authenticate(authorization)?;
+        grant.check(scope)?;
+        Ok(grant)
+    }
+
+    pub fn is_protected(&self, path: &str) -> bool {
+        self.is_enabled() && self.protected_paths.is_match(normalize_path(path))
+    }
+
+    /// Interceptor putting the caller's `Grant` into the request extensions,
+    /// every rpc then checks the scope it needs with [`require`].
+    #[allow(clippy::result_large_err)]
+    pub fn interceptor(
+        self: &Arc<Self>,
+    ) -> impl FnMut(tonic::Request<()>) -> Result<tonic::Request<()>, Status> + Clone {
+        let auth = self.clone();
+        move |mut request: tonic::Request<()>| {
+            let authorization = request
+                .metadata()
+                .get(AUTHORIZATION.as_str())
+                .and_then(|v| v.to_str().ok());
+            let grant = auth.authenticate(authorization)?;
+            request.extensions_mut().insert(grant);
+            Ok(request)
+        }
+    }
+}
+
+fn auth_error(e: HubError) -> Response {
+    let status = match e {
+        HubError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+        _ => StatusCode::UNAUTHORIZED,
+    };
+    (status, e.to_string()).into_response()
+}
+
+/// Axum middleware for static files, only paths matching `protect_path`
+/// need a token.
+pub async fn protect_static(
+    State(auth): State<Arc<Authenticator>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if auth.is_protected(request.uri().path()) {
+        if let Err(e) = auth.authorize(request.headers(), Scope::Read) {
+            return auth_error(e);
+        }
+    }
+    next.run(request).await
+}
+
+/// Decodes and normalizes a request path the way `ServeDir` resolves it, so
+/// `/a//b` or `/%61/b` can not slip past a pattern for `a/b`.
+pub fn normalize_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded)
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use crate::file::{path_is_valid, stream_to_file};
+use crate::file::{path_is_valid, stream_to_file, MAX_UPLOAD_SIZE};
 use crate::server::MyExtensionHub;
 
 use axum::extract::DefaultBodyLimit;
@@ -11,13 +11,23 @@ use axum::{
     body::Body,
     extract::{Multipart, Path, State},
     http::{
-        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
-        HeaderMap, StatusCode,
+        header::{
+            ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+            IF_NONE_MATCH, IF_RANGE, RANGE,
+        },
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
     },
     response::IntoResponse,
     routing::{get, post},
-    Router,
+    Json, Router,
 };
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use std::io::SeekFrom;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio_util::io::ReaderStream;
 use tower_http::limit::RequestBodyLimitLayer;
 
@@ -26,12 +36,11 @@ async fn upload(
     Path(hash): Path<String>,
     mut multipart: Multipart,
 ) -> Result<(), StatusCode> {
-    let config = state
-        .context
-        .upload_path_map
-        .get(&hash)
-        .ok_or(StatusCode::NOT_FOUND)?;
-    if state.get_tar_hash(&hash).is_ok() {
+    let config = state.verify_upload_url(&hash).map_err(|e| {
+        tracing::error!("Error: {:?}", e);
+        StatusCode::FORBIDDEN
+    })?;
+    if state.get_tar_hash(&config.tar_hash).is_ok() {
         if let Some(un_tar) = &config.un_tar {
             return state
                 .un_tar_to_dir(
@@ -50,32 +59,42 @@ async fn upload(
     };
 
     while let Some(field) = multipart.next_field().await.unwrap() {
-        let file_name = format!("{}.tar.gz", config.tar_hash);
+        // concurrent uploads of one tar must not share a temp file
+        let suffix: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+        let file_name = format!("{}.{}.part", config.tar_hash, suffix);
         path_is_valid(&file_name).map_err(|e| {
             tracing::error!("Error: {:?}", e);
             StatusCode::BAD_REQUEST
         })?;
-        let target_path = state.config.tar_dir_path.join(&file_name);
         let mut tmp_file = state.config.tar_dir_path.join("__tmp__");
         tmp_file.push(&file_name);
-        let path = stream_to_file(
+        let received = stream_to_file(
             tmp_file,
             field.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err)),
+            MAX_UPLOAD_SIZE,
         )
         .await
         .map_err(|e| {
             tracing::error!("Error: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            match e {
+                HubError::UploadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            }
         })?;
 
         let result = state
-            .upload_tar_by_path(&hash, &path, &target_path)
+            .upload_tar_by_path(&config, &received)
             .await
             .map_err(|e| {
                 tracing::error!("Error: {:?}", e);
                 println!("Error: {:?}", e);
                 match e {
                     HubError::ResourceNotFount => StatusCode::NOT_FOUND,
+                    HubError::UnsupportedArchive => StatusCode::BAD_REQUEST,
                     _ => StatusCode::INTERNAL_SERVER_ERROR,
                 }
             });
@@ -84,47 +103,320 @@ async fn upload(
     Ok(())
 }
 
+/// `GET /file/:hash` sends a tar, or the part asked for with a single
+/// `Range`. Tars are stored under their blake3 hash, so it is their strong
+/// ETag and their `Repr-Digest`. Only responses with content count as a use
+/// of the url, `HEAD`, `304` and `416` do not.
 async fn download(
     State(state): State<Arc<MyExtensionHub>>,
     Path(hash): Path<String>,
+    method: Method,
+    request_headers: HeaderMap,
 ) -> impl IntoResponse {
-    let (tar_hash, file) = match state.get_download_tar_path(&hash) {
-        Ok((tar_hash, file)) => (tar_hash, file),
-        Err(e) => {
-            tracing::error!("Error: {:?}", e);
-            return match e {
-                HubError::ResourceNotFount => Err((StatusCode::NOT_FOUND, e.to_string())),
-                _ => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {:?}", e))),
-            };
-        }
+    let head = method == Method::HEAD;
+    let (tar_hash, file, format) = match state.get_download_tar_path(&hash) {
+        Ok(found) => found,
+        Err(e) => return Err(download_error(e)),
     };
-    let file_name = format!("attachment; filename=\"{}.tar.gz\"", tar_hash);
+    let etag = format!("\"{}\"", tar_hash);
     let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, "application/gzip".parse().unwrap());
+    headers.insert(ETAG, etag.parse().unwrap());
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Ok(digest) = blake3::Hash::from_hex(&tar_hash) {
+        let digest = format!("blake3=:{}:", STANDARD.encode(digest.as_bytes()));
+        headers.insert(REPR_DIGEST, digest.parse().unwrap());
+    }
+    let if_none_match = request_headers
+        .get(IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+    if if_none_match.is_some_and(|v| etag_matches(v, &etag)) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    let file_name = format!("attachment; filename=\"{}{}\"", tar_hash, format.suffix());
+    headers.insert(CONTENT_TYPE, format.content_type().parse().unwrap());
     headers.insert(CONTENT_DISPOSITION, file_name.parse().unwrap());
-    // convert the `AsyncRead` into a `Stream`
-    let file = match tokio::fs::File::open(file).await {
+    let mut file = match tokio::fs::File::open(file).await {
         Ok(file) => file,
         Err(e) => {
             tracing::error!("Error: {:?}", e);
             return Err((StatusCode::NOT_FOUND, format!("File not found: {}", e)));
         }
     };
+    let len = match file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {:?}", e))),
+    };
 
-    let stream = ReaderStream::new(file);
+    // a range of an older version of the file must not be mixed with this one
+    let if_range_matches = request_headers
+        .get(IF_RANGE)
+        .is_none_or(|v| v.to_str().is_ok_and(|v| v.trim() == etag));
+    let range = match request_headers.get(RANGE).and_then(|v| v.to_str().ok()) {
+        Some(range) if if_range_matches => byte_range(range, len),
+        _ => ByteRange::Full,
+    };
+    let (status, start, length) = match range {
+        ByteRange::Full => (StatusCode::OK, 0, len),
+        ByteRange::Partial(start, end) => {
+            let content_range = format!("bytes {}-{}/{}", start, end, len);
+            headers.insert(CONTENT_RANGE, content_range.parse().unwrap());
+            (StatusCode::PARTIAL_CONTENT, start, end - start + 1)
+        }
+        ByteRange::Unsatisfiable => {
+            let content_range = format!("bytes */{}", len);
+            headers.insert(CONTENT_RANGE, content_range.parse().unwrap());
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+        }
+    };
+    headers.insert(CONTENT_LENGTH, length.into());
+    if head {
+        return Ok((status, headers).into_response());
+    }
+    if let Err(e) = state.count_download(&hash) {
+        return Err(download_error(e));
+    }
+    if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {:?}", e)));
+    }
+
+    // convert the `AsyncRead` into a `Stream`
+    let stream = ReaderStream::new(file.take(length));
     // convert the `Stream` into an `axum::body::HttpBody`
     let body = Body::from_stream(stream);
-    Ok((headers, body).into_response())
+    Ok((status, headers, body).into_response())
+}
+
+fn download_error(e: HubError) -> (StatusCode, String) {
+    tracing::error!("Error: {:?}", e);
+    match e {
+        HubError::ResourceNotFount => (StatusCode::NOT_FOUND, e.to_string()),
+        HubError::InvalidUrl(_) => (StatusCode::FORBIDDEN, e.to_string()),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {:?}", e)),
+    }
+}
+
+const REPR_DIGEST: HeaderName = HeaderName::from_static("repr-digest");
+
+/// What part of a file a `Range` header asks for.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// No range, or one that is not understood, e.g. several ranges
+    Full,
+    /// First and last byte, both included
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Reads a single `bytes=` range of a file of `len` bytes.
+fn byte_range(value: &str, len: u64) -> ByteRange {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    let Some((first, last)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let (first, last) = (first.trim(), last.trim());
+    let (start, end) = if first.is_empty() {
+        // the last `last` bytes
+        match last.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (len.saturating_sub(suffix), len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        }
+    } else {
+        let Ok(start) = first.parse::<u64>() else {
+            return ByteRange::Full;
+        };
+        let end = match last {
+            "" => len.saturating_sub(1),
+            last => match last.parse::<u64>() {
+                Ok(end) if end >= start => end.min(len.saturating_sub(1)),
+                _ => return ByteRange::Full,
+            },
+        };
+        (start, end)
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+/// Whether an `If-None-Match` value names `etag`, weak tags match too.
+fn etag_matches(value: &str, etag: &str) -> bool {
+    value
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+const UPLOAD_OFFSET: &str = "upload-offset";
+
+#[derive(Serialize)]
+struct UploadSessionInfo {
+    session: String,
+    offset: u64,
+}
+
+fn session_error(e: HubError) -> (StatusCode, String) {
+    tracing::error!("Error: {:?}", e);
+    let status = match e {
+        HubError::ResourceNotFount => StatusCode::NOT_FOUND,
+        HubError::InvalidUrl(_) => StatusCode::FORBIDDEN,
+        HubError::OffsetNotMatch(_, _) => StatusCode::CONFLICT,
+        HubError::UploadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        HubError::HashNotMatch(_, _) | HubError::InvalidPath(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
+}
+
+fn session_response(session: String, offset: u64) -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(UPLOAD_OFFSET, offset.into());
+    (headers, Json(UploadSessionInfo { session, offset }))
 }
 
+/// `POST /upload/:url` starts a resumable upload for an upload url.
+async fn create_upload_session(
+    State(state): State<Arc<MyExtensionHub>>,
+    Path(url): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let session = state
+        .create_upload_session(&url)
+        .await
+        .map_err(session_error)?;
+    Ok(session_response(session, 0))
+}
+
+/// `GET /upload/session/:id` reports how many bytes have been received.
+async fn upload_session_offset(
+    State(state): State<Arc<MyExtensionHub>>,
+    Path(session_id): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let session = state.upload_session(&session_id).map_err(session_error)?;
+    let offset = session.lock().await.offset;
+    Ok(session_response(session_id, offset))
+}
+
+/// `PUT /upload/session/:id` appends the body at the `Upload-Offset` header.
+async fn upload_session_chunk(
+    State(state): State<Arc<MyExtensionHub>>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let offset = headers
+        .get(UPLOAD_OFFSET)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or((
+            StatusCode::BAD_REQUEST,
+            format!("Missing or invalid {} header", UPLOAD_OFFSET),
+        ))?;
+    let session = state.upload_session(&session_id).map_err(session_error)?;
+    let offset = session
+        .lock()
+        .await
+        .append(
+            offset,
+            body.into_data_stream().map_err(std::io::Error::other),
+        )
+        .await
+        .map_err(session_error)?;
+    Ok(session_response(session_id, offset))
+}
+
+/// `POST /upload/session/:id/finalize` verifies the hash and installs the tar.
+async fn finalize_upload_session(
+    State(state): State<Arc<MyExtensionHub>>,
+    Path(session_id): Path<String>,
+) -> Result<(), (StatusCode, String)> {
+    state
+        .finalize_upload_session(&session_id)
+        .await
+        .map_err(session_error)
+}
+
+/// Every route but `/version` takes an url signed by `UploadTar` or
+/// `DownloadTar`, or a session started with one. The signature is what
+/// authorizes the request, the token was checked when the url was handed
+/// out, so whoever the url is passed to needs no token of their own.
 pub fn router(state: Arc<MyExtensionHub>) -> Router {
     Router::new()
         .route("/version", get(|| async { "0.1.0" }))
-        .route("/file/:hash", get(download))
-        .route("/file/:hash", post(upload))
+        .route("/file/:hash", get(download).post(upload))
+        .route("/upload/:url", post(create_upload_session))
+        .route(
+            "/upload/session/:id",
+            get(upload_session_offset).put(upload_session_chunk),
+        )
+        .route(
+            "/upload/session/:id/finalize",
+            post(finalize_upload_session),
+        )
         .with_state(state.clone())
         .layer(DefaultBodyLimit::disable())
-        .layer(RequestBodyLimitLayer::new(
-            250 * 1024 * 1024, /* 250mb */
-        ))
+        .layer(RequestBodyLimitLayer::new(MAX_UPLOAD_SIZE as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_range_reads_single_ranges() {
+        assert_eq!(byte_range("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(byte_range(" bytes= 5 - 5 ", 100), ByteRange::Partial(5, 5));
+        assert_eq!(byte_range("bytes=90-", 100), ByteRange::Partial(90, 99));
+        assert_eq!(byte_range("bytes=90-1000", 100), ByteRange::Partial(90, 99));
+    }
+
+    #[test]
+    fn byte_range_reads_suffix_ranges() {
+        assert_eq!(byte_range("bytes=-10", 100), ByteRange::Partial(90, 99));
+        assert_eq!(byte_range("bytes=-1000", 100), ByteRange::Partial(0, 99));
+        assert_eq!(byte_range("bytes=-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range("bytes=-10", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn byte_range_past_the_end_is_unsatisfiable() {
+        assert_eq!(byte_range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range("bytes=100-200", 100), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn byte_range_ignores_what_it_does_not_understand() {
+        for value in [
+            "",
+            "bytes",
+            "bytes=",
+            "bytes=-",
+            "bytes=5",
+            "bytes=a-b",
+            "bytes=-x",
+            "bytes=1-x",
+            "bytes=-1-2",
+            "bytes=9-5",
+            "bytes=0-1,5-6",
+            "items=0-9",
+            "Bytes=0-9",
+        ] {
+            assert_eq!(byte_range(value, 100), ByteRange::Full, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn etag_matches_lists_and_weak_tags() {
+        assert!(etag_matches("\"a\"", "\"a\""));
+        assert!(etag_matches("\"b\", W/\"a\"", "\"a\""));
+        assert!(etag_matches("*", "\"a\""));
+        assert!(!etag_matches("\"b\"", "\"a\""));
+    }
 }
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use extension_hub::abi::extension_hub as abi;
+use extension_hub::error::HubError;
+
+use crate::release::ReleaseHistory;
+use crate::server::MyExtensionHub;
+
+extern crate extension_hub;
+
+/// Optional manifest at the root of an extension tar.
+pub const MANIFEST_FILE: &str = "extension.json";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtensionManifest {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default, alias = "entryPoint", alias = "entry_point")]
+    pub entry: String,
+}
+
+impl ExtensionManifest {
+    /// Reads the manifest of an unpacked extension. A missing manifest is not
+    /// an error, a malformed one is logged and ignored.
+    pub fn read(dir: impl AsRef<Path>) -> Option<Self> {
+        let path = dir.as_ref().join(MANIFEST_FILE);
+        let bytes = std::fs::read(&path).ok()?;
+        serde_json::from_slice(&bytes)
+            .inspect_err(|e| tracing::warn!("Ignore invalid manifest {:?}: {:?}", path, e))
+            .ok()
+    }
+
+    pub fn to_abi(&self) -> abi::ExtensionManifest {
+        abi::ExtensionManifest {
+            name: self.name.clone(),
+            version: self.version.clone(),
+            description: self.description.clone(),
+            entry: self.entry.clone(),
+        }
+    }
+}
+
+fn extension_info(item_dir: &str, history: &ReleaseHistory) -> Option<abi::Extension> {
+    let release = history.current_release()?;
+    Some(abi::Extension {
+        target_dir: item_dir.to_owned(),
+        tar_hash: release.tar_hash.clone(),
+        release_id: release.id.clone(),
+        deployed_at: history.deployed_at,
+        size: release.size,
+        manifest: release.manifest.as_ref().map(ExtensionManifest::to_abi),
+    })
+}
+
+impl MyExtensionHub {
+    pub fn list_extensions(&self) -> Vec<abi::Extension> {
+        let mut extensions: Vec<_> = self
+            .context
+            .releases
+            .iter()
+            .filter_map(|e| extension_info(e.key(), e.value()))
+            .collect();
+        extensions.sort_by(|a, b| a.target_dir.cmp(&b.target_dir));
+        extensions
+    }
+
+    pub fn get_extension(&self, item_dir: &str) -> Result<abi::Extension, HubError> {
+        self.context
+            .releases
+            .get(item_dir)
+            .and_then(|history| extension_info(item_dir, &history))
+            .ok_or(HubError::DirNotExist(item_dir.to_owned()))
+    }
+}
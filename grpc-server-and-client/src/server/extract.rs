@@ -0,0 +1,573 @@
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, EntryType};
+
+use extension_hub::error::HubError;
+
+use crate::archive::ArchiveFormat;
+
+extern crate extension_hub;
+
+/// What an uploaded archive is allowed to contain.
+#[derive(Parser, Debug, Serialize, Deserialize, Clone)]
+pub struct ExtractPolicy {
+    /// Maximum total size of the unpacked entries, in bytes
+    #[arg(long, default_value_t = default_max_unpacked_size())]
+    #[serde(default = "default_max_unpacked_size")]
+    pub max_unpacked_size: u64,
+    /// Maximum number of entries in an archive
+    #[arg(long, default_value_t = default_max_entries())]
+    #[serde(default = "default_max_entries")]
+    pub max_entries: u64,
+    /// Strip leading `/` from absolute entry paths instead of rejecting them
+    #[arg(long)]
+    #[serde(default)]
+    pub normalize_absolute_paths: bool,
+}
+
+fn default_max_unpacked_size() -> u64 {
+    1024 * 1024 * 1024 /* 1gb */
+}
+
+fn default_max_entries() -> u64 {
+    100_000
+}
+
+impl Default for ExtractPolicy {
+    fn default() -> Self {
+        ExtractPolicy {
+            max_unpacked_size: default_max_unpacked_size(),
+            max_entries: default_max_entries(),
+            normalize_absolute_paths: false,
+        }
+    }
+}
+
+impl ExtractPolicy {
+    /// Unpacks `archive` into `dst`, checking every entry before anything is
+    /// written for it:
+    /// - paths must stay inside `dst`, `..` is never accepted
+    /// - symlinks and hardlinks must point inside `dst`
+    /// - nothing is written at or below a symlink of the same archive, so a
+    ///   link can not redirect a later entry
+    /// - device files, fifos and sparse files are rejected
+    ///
+    /// Ownership is never restored, files get `0o644` (`0o755` when any
+    /// execute bit is set) and dirs `0o755`.
+    pub fn unpack<R: Read>(&self, archive: &mut Archive<R>, dst: &Path) -> Result<(), HubError> {
+        archive.set_preserve_permissions(false);
+        archive.set_preserve_ownerships(false);
+        archive.set_unpack_xattrs(false);
+        std::fs::create_dir_all(dst)?;
+
+        let mut entries = 0;
+        let mut unpacked_size = 0;
+        let mut symlinks = HashSet::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            entries += 1;
+            if entries > self.max_entries {
+                return Err(HubError::TooManyEntries(self.max_entries));
+            }
+            unpacked_size += entry.header().size()?;
+            if unpacked_size > self.max_unpacked_size {
+                return Err(HubError::ArchiveTooLarge(self.max_unpacked_size));
+            }
+
+            let raw_path = entry.path()?.to_string_lossy().to_string();
+            let path = self.checked_path(&raw_path, &entry.path()?)?;
+            check_not_below_symlink(&symlinks, &raw_path, &path)?;
+            let entry_type = entry.header().entry_type();
+            match entry_type {
+                EntryType::Regular | EntryType::Continuous | EntryType::Directory => {}
+                EntryType::Symlink => {
+                    let link = entry
+                        .link_name()?
+                        .ok_or_else(|| unsafe_entry(&raw_path, "symlink without target"))?;
+                    if link.is_absolute() {
+                        return Err(unsafe_entry(&raw_path, "symlink to an absolute path"));
+                    }
+                    let parent = path.parent().unwrap_or(Path::new(""));
+                    if normalize(&parent.join(&link)).is_none() {
+                        return Err(unsafe_entry(&raw_path, "symlink points outside"));
+                    }
+                    symlinks.insert(path.clone());
+                }
+                EntryType::Link => {
+                    let link = entry
+                        .link_name()?
+                        .ok_or_else(|| unsafe_entry(&raw_path, "hardlink without target"))?;
+                    let link = self.checked_path(&raw_path, &link)?;
+                    check_not_below_symlink(&symlinks, &raw_path, &link)?;
+                }
+                EntryType::XGlobalHeader | EntryType::XHeader => continue,
+                _ => {
+                    return Err(unsafe_entry(
+                        &raw_path,
+                        &format!("unsupported entry type {:?}", entry_type),
+                    ))
+                }
+            }
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+
+            if !entry.unpack_in(dst)? {
+                return Err(unsafe_entry(&raw_path, "path points outside"));
+            }
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = match entry_type {
+                    EntryType::Directory => Some(0o755),
+                    EntryType::Regular | EntryType::Continuous => {
+                        let executable = entry.header().mode()? & 0o111 != 0;
+                        Some(if executable { 0o755 } else { 0o644 })
+                    }
+                    _ => None,
+                };
+                if let Some(mode) = mode {
+                    std::fs::set_permissions(
+                        dst.join(&path),
+                        std::fs::Permissions::from_mode(mode),
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Unpacks the stored file at `path` into `dst`, whatever its format.
+    pub fn unpack_file(
+        &self,
+        path: &Path,
+        format: ArchiveFormat,
+        dst: &Path,
+    ) -> Result<(), HubError> {
+        match format.open_tar(path)? {
+            Some(mut archive) => self.unpack(&mut archive, dst),
+            None => self.unpack_zip(path, dst),
+        }
+    }
+
+    /// Same checks and permissions as [`Self::unpack`], for a zip file.
+    fn unpack_zip(&self, path: &Path, dst: &Path) -> Result<(), HubError> {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let mut zip =
+            zip::ZipArchive::new(std::fs::File::open(path)?).map_err(std::io::Error::from)?;
+        if zip.len() as u64 > self.max_entries {
+            return Err(HubError::TooManyEntries(self.max_entries));
+        }
+        std::fs::create_dir_all(dst)?;
+        let root = dst.canonicalize()?;
+
+        let mut unpacked_size = 0;
+        let mut symlinks = HashSet::new();
+        for index in 0..zip.len() {
+            let mut entry = zip.by_index(index).map_err(std::io::Error::from)?;
+            // the size a zip claims is not enforced while inflating, only the
+            // bytes actually read count against the limit
+            let remaining = self.max_unpacked_size - unpacked_size;
+            if entry.size() > remaining {
+                return Err(HubError::ArchiveTooLarge(self.max_unpacked_size));
+            }
+
+            let raw_path = entry.name().to_owned();
+            let path = self.checked_path(&raw_path, Path::new(&raw_path))?;
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            check_not_below_symlink(&symlinks, &raw_path, &path)?;
+            let target = dst.join(&path);
+            if entry.is_dir() {
+                std::fs::create_dir_all(&target)?;
+                std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o755))?;
+                continue;
+            }
+            let parent = target.parent().unwrap_or(dst);
+            std::fs::create_dir_all(parent)?;
+            // an earlier symlink entry may have redirected the parent
+            if !parent.canonicalize()?.starts_with(&root) {
+                return Err(unsafe_entry(&raw_path, "path points outside"));
+            }
+
+            if entry.is_symlink() {
+                let mut link = String::new();
+                unpacked_size += (&mut entry).take(remaining + 1).read_to_string(&mut link)? as u64;
+                if unpacked_size > self.max_unpacked_size {
+                    return Err(HubError::ArchiveTooLarge(self.max_unpacked_size));
+                }
+                let link = Path::new(&link);
+                if link.is_absolute() {
+                    return Err(unsafe_entry(&raw_path, "symlink to an absolute path"));
+                }
+                let parent = path.parent().unwrap_or(Path::new(""));
+                if normalize(&parent.join(link)).is_none() {
+                    return Err(unsafe_entry(&raw_path, "symlink points outside"));
+                }
+                symlink(link, &target)?;
+                symlinks.insert(path);
+                continue;
+            }
+
+            let executable = entry.unix_mode().unwrap_or(0) & 0o111 != 0;
+            let mut file = std::fs::File::create(&target)?;
+            unpacked_size += std::io::copy(&mut (&mut entry).take(remaining + 1), &mut file)?;
+            if unpacked_size > self.max_unpacked_size {
+                return Err(HubError::ArchiveTooLarge(self.max_unpacked_size));
+            }
+            file.set_permissions(std::fs::Permissions::from_mode(if executable {
+                0o755
+            } else {
+                0o644
+            }))?;
+        }
+        Ok(())
+    }
+
+    /// The normalized relative form of an entry path, or an error when the
+    /// path could leave the target dir.
+    fn checked_path(&self, raw_path: &str, path: &Path) -> Result<PathBuf, HubError> {
+        if path.has_root() && !self.normalize_absolute_paths {
+            return Err(unsafe_entry(raw_path, "absolute path"));
+        }
+        let relative: PathBuf = path
+            .components()
+            .filter(|c| !matches!(c, Component::Prefix(_) | Component::RootDir))
+            .collect();
+        if relative.components().any(|c| c == Component::ParentDir) {
+            return Err(unsafe_entry(raw_path, "path contains '..'"));
+        }
+        normalize(&relative).ok_or_else(|| unsafe_entry(raw_path, "path points outside"))
+    }
+}
+
+/// Lexically resolves `.` and `..` in a relative path, `None` when it climbs
+/// above its root.
+pub fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::Prefix(_) | Component::RootDir => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// Rejects entries at or below a symlink unpacked earlier. Symlink targets
+/// are only checked against the lexical parent, which holds as long as no
+/// parent is a link.
+fn check_not_below_symlink(
+    symlinks: &HashSet<PathBuf>,
+    raw_path: &str,
+    path: &Path,
+) -> Result<(), HubError> {
+    if path.ancestors().any(|ancestor| symlinks.contains(ancestor)) {
+        return Err(unsafe_entry(raw_path, "path below a symlink"));
+    }
+    Ok(())
+}
+
+fn unsafe_entry(path: &str, reason: &str) -> HubError {
+    HubError::UnsafeEntry(path.to_owned(), reason.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use tar::{Builder, Header};
+
+    /// An entry written with raw header fields, so paths `tar::Builder`
+    /// refuses can be tested too.
+    struct Entry<'a> {
+        path: &'a str,
+        kind: EntryType,
+        link: &'a str,
+        data: &'a [u8],
+        mode: u32,
+    }
+
+    fn file<'a>(path: &'a str, data: &'a [u8]) -> Entry<'a> {
+        Entry {
+            path,
+            kind: EntryType::Regular,
+            link: "",
+            data,
+            mode: 0o600,
+        }
+    }
+
+    fn link<'a>(path: &'a str, kind: EntryType, target: &'a str) -> Entry<'a> {
+        Entry {
+            path,
+            kind,
+            link: target,
+            data: b"",
+            mode: 0o777,
+        }
+    }
+
+    fn tar(entries: &[Entry]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for entry in entries {
+            let mut header = Header::new_gnu();
+            let gnu = header.as_gnu_mut().unwrap();
+            gnu.name[..entry.path.len()].copy_from_slice(entry.path.as_bytes());
+            gnu.linkname[..entry.link.len()].copy_from_slice(entry.link.as_bytes());
+            header.set_entry_type(entry.kind);
+            header.set_size(entry.data.len() as u64);
+            header.set_mode(entry.mode);
+            header.set_cksum();
+            builder.append(&header, entry.data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn unpack(
+        policy: &ExtractPolicy,
+        entries: &[Entry],
+    ) -> (tempfile::TempDir, Result<(), HubError>) {
+        let root = tempfile::tempdir().unwrap();
+        let bytes = tar(entries);
+        let result = policy.unpack(
+            &mut Archive::new(bytes.as_slice()),
+            &root.path().join("dst"),
+        );
+        (root, result)
+    }
+
+    fn assert_unsafe(result: Result<(), HubError>, reason: &str) {
+        match result {
+            Err(HubError::UnsafeEntry(_, r)) => assert!(r.contains(reason), "{}", r),
+            other => panic!("expected unsafe entry '{}', got {:?}", reason, other),
+        }
+    }
+
+    #[test]
+    fn normalize_resolves_dots() {
+        assert_eq!(
+            normalize(Path::new("a/./b/../c")),
+            Some(PathBuf::from("a/c"))
+        );
+        assert_eq!(normalize(Path::new("a/..")), Some(PathBuf::new()));
+        assert_eq!(normalize(Path::new("")), Some(PathBuf::new()));
+        assert_eq!(normalize(Path::new("a/../..")), None);
+        assert_eq!(normalize(Path::new("../a")), None);
+        assert_eq!(normalize(Path::new("/a")), None);
+    }
+
+    #[test]
+    fn unpacks_files_with_fixed_modes() {
+        let mut exec = file("bin/run", b"#!/bin/sh");
+        exec.mode = 0o700;
+        let (root, result) = unpack(
+            &ExtractPolicy::default(),
+            &[file("./index.html", b"hi"), exec],
+        );
+        result.unwrap();
+        let dst = root.path().join("dst");
+        assert_eq!(std::fs::read(dst.join("index.html")).unwrap(), b"hi");
+        let mode = |p: &str| std::fs::metadata(dst.join(p)).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode("index.html"), 0o644);
+        assert_eq!(mode("bin/run"), 0o755);
+    }
+
+    #[test]
+    fn rejects_parent_and_absolute_paths() {
+        let policy = ExtractPolicy::default();
+        assert_unsafe(unpack(&policy, &[file("../x", b"")]).1, "'..'");
+        assert_unsafe(unpack(&policy, &[file("a/../../x", b"")]).1, "'..'");
+        assert_unsafe(unpack(&policy, &[file("/etc/x", b"")]).1, "absolute");
+    }
+
+    #[test]
+    fn normalizes_absolute_paths_when_allowed() {
+        let policy = ExtractPolicy {
+            normalize_absolute_paths: true,
+            ..Default::default()
+        };
+        let (root, result) = unpack(&policy, &[file("/etc/x", b"x")]);
+        result.unwrap();
+        assert!(root.path().join("dst/etc/x").is_file());
+        assert!(!root.path().join("etc").exists());
+    }
+
+    #[test]
+    fn symlinks_must_stay_inside() {
+        let policy = ExtractPolicy::default();
+        let (root, result) = unpack(&policy, &[link("a/b/up", EntryType::Symlink, "../..")]);
+        result.unwrap();
+        assert!(root.path().join("dst/a/b/up").is_symlink());
+        assert_unsafe(
+            unpack(&policy, &[link("a/up", EntryType::Symlink, "../..")]).1,
+            "outside",
+        );
+        assert_unsafe(
+            unpack(&policy, &[link("a", EntryType::Symlink, "/etc")]).1,
+            "absolute",
+        );
+    }
+
+    #[test]
+    fn rejects_entries_below_a_symlink() {
+        let policy = ExtractPolicy::default();
+        // each link is harmless on its own, the second one resolves from
+        // the dst root through the first
+        let (root, result) = unpack(
+            &policy,
+            &[
+                link("a/b/c/d/up", EntryType::Symlink, "../../../.."),
+                link("a/b/c/d/up/x", EntryType::Symlink, "../../../.."),
+            ],
+        );
+        assert_unsafe(result, "below a symlink");
+        assert!(!root.path().join("dst/x").exists());
+        assert_unsafe(
+            unpack(
+                &policy,
+                &[link("a/up", EntryType::Symlink, ".."), file("a/up/f", b"")],
+            )
+            .1,
+            "below a symlink",
+        );
+        assert_unsafe(
+            unpack(
+                &policy,
+                &[link("a", EntryType::Symlink, "b"), file("a", b"")],
+            )
+            .1,
+            "below a symlink",
+        );
+    }
+
+    #[test]
+    fn hardlinks_must_stay_inside() {
+        let policy = ExtractPolicy::default();
+        let (root, result) = unpack(&policy, &[file("a", b"x"), link("b", EntryType::Link, "a")]);
+        result.unwrap();
+        assert_eq!(std::fs::read(root.path().join("dst/b")).unwrap(), b"x");
+        assert_unsafe(
+            unpack(&policy, &[link("b", EntryType::Link, "../a")]).1,
+            "'..'",
+        );
+        assert_unsafe(
+            unpack(
+                &policy,
+                &[
+                    link("up", EntryType::Symlink, "."),
+                    link("b", EntryType::Link, "up/a"),
+                ],
+            )
+            .1,
+            "below a symlink",
+        );
+    }
+
+    #[test]
+    fn rejects_special_files() {
+        let policy = ExtractPolicy::default();
+        for kind in [EntryType::Char, EntryType::Block, EntryType::Fifo] {
+            assert_unsafe(unpack(&policy, &[link("dev", kind, "")]).1, "unsupported");
+        }
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let policy = ExtractPolicy {
+            max_entries: 1,
+            max_unpacked_size: 4,
+            normalize_absolute_paths: false,
+        };
+        unpack(&policy, &[file("a", b"1234")]).1.unwrap();
+        assert!(matches!(
+            unpack(&policy, &[file("a", b"1"), file("b", b"2")]).1,
+            Err(HubError::TooManyEntries(1))
+        ));
+        assert!(matches!(
+            unpack(&policy, &[file("a", b"12345")]).1,
+            Err(HubError::ArchiveTooLarge(4))
+        ));
+    }
+
+    fn unpack_zip(entries: &[(&str, Option<&str>)]) -> (tempfile::TempDir, Result<(), HubError>) {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("a.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        for (name, target) in entries {
+            match target {
+                Some(target) => zip.add_symlink(*name, *target, options).unwrap(),
+                None => {
+                    zip.start_file(*name, options).unwrap();
+                    zip.write_all(b"x").unwrap();
+                }
+            }
+        }
+        zip.finish().unwrap();
+        let result = ExtractPolicy::default().unpack_file(
+            &path,
+            ArchiveFormat::Zip,
+            &root.path().join("dst"),
+        );
+        (root, result)
+    }
+
+    #[test]
+    fn zip_symlinks_are_checked_like_tar() {
+        let (root, result) = unpack_zip(&[("a/index.html", None), ("a/up", Some(".."))]);
+        result.unwrap();
+        assert!(root.path().join("dst/a/up").is_symlink());
+        assert_unsafe(unpack_zip(&[("a/up", Some("../.."))]).1, "outside");
+        let (root, result) = unpack_zip(&[
+            ("a/b/c/d/up", Some("../../../..")),
+            ("a/b/c/d/up/x", Some("../../../..")),
+        ]);
+        assert_unsafe(result, "below a symlink");
+        assert!(!root.path().join("dst/x").exists());
+        assert_unsafe(
+            unpack_zip(&[("up", Some(".")), ("up/f", None)]).1,
+            "below a symlink",
+        );
+    }
+
+    #[test]
+    fn zip_sizes_are_counted_as_inflated() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("bomb.zip");
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("bomb", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(&vec![0; 1024 * 1024]).unwrap();
+        let mut bytes = zip.finish().unwrap().into_inner();
+        // claim 1 byte in the local and the central header
+        for (signature, offset) in [(b"PK\x03\x04", 22), (b"PK\x01\x02", 24)] {
+            let header = bytes.windows(4).position(|w| w == signature).unwrap();
+            bytes[header + offset..header + offset + 4].copy_from_slice(&1u32.to_le_bytes());
+        }
+        std::fs::write(&path, bytes).unwrap();
+
+        let policy = ExtractPolicy {
+            max_unpacked_size: 1024,
+            ..Default::default()
+        };
+        let dst = root.path().join("dst");
+        assert!(matches!(
+            policy.unpack_file(&path, ArchiveFormat::Zip, &dst),
+            Err(HubError::ArchiveTooLarge(1024))
+        ));
+        assert!(std::fs::metadata(dst.join("bomb")).unwrap().len() <= 1025);
+    }
+}
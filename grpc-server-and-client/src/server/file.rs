@@ -1,8 +1,13 @@
 use bytes::Bytes;
-use futures::Stream;
+use futures::{Stream, TryStreamExt};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 use std::path::{Path, PathBuf};
-use tokio::{fs, fs::File, io::BufWriter};
-use tokio_util::io::StreamReader;
+use tokio::{
+    fs,
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+};
 
 use extension_hub::error::HubError;
 
@@ -25,7 +30,52 @@ pub fn path_is_valid(path: impl AsRef<Path>) -> Result<(), HubError> {
     Ok(())
 }
 
-pub async fn stream_to_file<S>(path: impl AsRef<Path>, stream: S) -> Result<PathBuf, HubError>
+/// A sibling of `path` named `.<name>.<tag>-<random>`, used to stage a dir
+/// before it is moved into place.
+pub fn sibling_path(path: impl AsRef<Path>, tag: &str) -> Result<PathBuf, HubError> {
+    let path = path.as_ref();
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(HubError::InvalidPath(path.to_string_lossy().into_owned()));
+    };
+    let suffix: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+    Ok(parent.join(format!(".{}.{}-{}", name.to_string_lossy(), tag, suffix)))
+}
+
+/// Total size of the regular files below `path`, symlinks are not followed.
+pub fn dir_size(path: impl AsRef<Path>) -> u64 {
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// A file written by [`stream_to_file`], hashed while it was written.
+#[derive(Debug)]
+pub struct ReceivedFile {
+    pub path: PathBuf,
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Largest tar accepted by an upload, over http or grpc.
+pub const MAX_UPLOAD_SIZE: u64 = 250 * 1024 * 1024; /* 250mb */
+
+/// Writes `stream` to `path` and computes its blake3 digest on the way, so
+/// the content never has to be held in memory or read back. A partially
+/// written file is removed when the stream fails or sends more than
+/// `max_size` bytes.
+pub async fn stream_to_file<S>(
+    path: impl AsRef<Path>,
+    stream: S,
+    max_size: u64,
+) -> Result<ReceivedFile, HubError>
 where
     S: Stream<Item = Result<Bytes, std::io::Error>>,
 {
@@ -35,7 +85,6 @@ where
     }
 
     if let Some(parent) = path.parent() {
-        dbg!(parent);
         if !parent.exists() {
             fs::create_dir_all(parent)
                 .await
@@ -43,18 +92,39 @@ where
         }
     }
 
-    // Convert the stream into an `AsyncRead`.
-    let body_with_io_error = stream;
-    let body_reader = StreamReader::new(body_with_io_error);
-    futures::pin_mut!(body_reader);
+    futures::pin_mut!(stream);
 
-    // Create the file. `File` implements `AsyncWrite`.
-    let mut file = BufWriter::new(File::create(&path).await?);
+    let received = async {
+        // Create the file. `File` implements `AsyncWrite`.
+        let mut file = BufWriter::new(File::create(&path).await?);
+        let mut hasher = blake3::Hasher::new();
+        let mut size = 0;
 
-    // Copy the body into the file.
-    tokio::io::copy(&mut body_reader, &mut file).await?;
+        // Copy the body into the file, hashing every chunk as it goes.
+        while let Some(bytes) = stream.try_next().await? {
+            if size + bytes.len() as u64 > max_size {
+                return Err(HubError::UploadTooLarge(max_size));
+            }
+            hasher.update(&bytes);
+            file.write_all(&bytes).await?;
+            size += bytes.len() as u64;
+        }
+        file.flush().await?;
+        Ok::<_, HubError>((hasher.finalize().to_hex().to_string(), size))
+    }
+    .await;
 
-    Ok(path.into())
+    match received {
+        Ok((hash, size)) => Ok(ReceivedFile {
+            path: path.into(),
+            hash,
+            size,
+        }),
+        Err(e) => {
+            let _ = fs::remove_file(path).await;
+            Err(e)
+        }
+    }
 }
 
 // pub async fn file_to_stream(
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::Metadata;
+use std::time::UNIX_EPOCH;
+
+use extension_hub::abi::extension_hub as abi;
+use extension_hub::error::HubError;
+
+use crate::archive::ArchiveFormat;
+use crate::server::MyExtensionHub;
+use crate::store::unix_now;
+
+extern crate extension_hub;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TarRecord {
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub uploaded_at: u64,
+    /// Last time the tar was untarred or downloaded
+    #[serde(default)]
+    pub last_used_at: u64,
+    /// How the stored file is packed, records written before other formats
+    /// were accepted are all tar.gz
+    #[serde(default)]
+    pub format: ArchiveFormat,
+}
+
+impl TarRecord {
+    /// A record for a tar found on disk without one, dated by its mtime.
+    pub fn from_metadata(metadata: &Metadata, format: ArchiveFormat) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        TarRecord {
+            size: metadata.len(),
+            uploaded_at: modified,
+            last_used_at: modified,
+            format,
+        }
+    }
+}
+
+impl MyExtensionHub {
+    pub fn touch_tar(&self, tar_hash: &str) {
+        if let Some(mut record) = self.context.tars.get_mut(tar_hash) {
+            record.last_used_at = unix_now();
+        }
+    }
+
+    fn tar_info(&self, tar_hash: &str, record: &TarRecord) -> abi::TarInfo {
+        let mut target_dirs: Vec<String> = self
+            .context
+            .item_dir_map
+            .get(tar_hash)
+            .map(|set| set.iter().map(|d| d.key().clone()).collect())
+            .unwrap_or_default();
+        target_dirs.sort();
+        abi::TarInfo {
+            tar_hash: tar_hash.to_owned(),
+            size: record.size,
+            uploaded_at: record.uploaded_at,
+            last_used_at: record.last_used_at,
+            target_dirs,
+            format: record.format.to_abi().into(),
+        }
+    }
+
+    pub fn list_tars(&self) -> Vec<abi::TarInfo> {
+        let mut tars: Vec<_> = self
+            .context
+            .tars
+            .iter()
+            .map(|e| self.tar_info(e.key(), e.value()))
+            .collect();
+        tars.sort_by_key(|t| std::cmp::Reverse(t.uploaded_at));
+        tars
+    }
+
+    pub fn get_tar_info(&self, tar_hash: &str) -> Result<abi::TarInfo, HubError> {
+        let record = self
+            .context
+            .tars
+            .get(tar_hash)
+            .map(|r| r.clone())
+            .ok_or(HubError::TarNotExist(tar_hash.to_owned()))?;
+        Ok(self.tar_info(tar_hash, &record))
+    }
+
+    /// Deletes a tar no dir is deployed from, and the objects only it used.
+    pub fn delete_tar(&self, tar_hash: &str) -> Result<abi::ClearData, HubError> {
+        let info = self.get_tar_info(tar_hash)?;
+        if !info.target_dirs.is_empty() {
+            return Err(HubError::TarInUse(
+                tar_hash.to_owned(),
+                info.target_dirs.join(", "),
+            ));
+        }
+        let path = self
+            .tar_path(tar_hash)
+            .ok_or(HubError::InvalidPath(tar_hash.to_owned()))?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let removed = vec![file_name.to_string()];
+        let size = std::fs::metadata(&path)
+            .map(|m| m.len())
+            .unwrap_or_default();
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        self.context.tars.remove(tar_hash);
+        self.context.item_dir_map.remove(tar_hash);
+        self.persist()?;
+        let dropped = HashSet::from([tar_hash.to_owned()]);
+        let keep_since = unix_now().saturating_sub(self.config.max_upload_url_ttl_secs);
+        let (objects_removed, objects_size) = self.objects.prune(&dropped, keep_since, false)?;
+        Ok(abi::ClearData {
+            removed,
+            freed_bytes: size + objects_size,
+            objects_removed,
+        })
+    }
+}
@@ -1,13 +1,13 @@
-#![feature(duration_constructors)]
-
 use std::{net::SocketAddr, sync::Arc};
 
 use extension_hub::abi::extension_hub::extension_hub_server::ExtensionHubServer;
+use auth::Authenticator;
 use server::{MyExtensionHub, MyExtensionHubConfig};
 
 use axum::Router;
 use clap::Parser;
 use static_files::wrap_files_router;
+use tonic::service::interceptor::InterceptedService;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use figment::{
@@ -18,10 +18,20 @@ use serde::{Deserialize, Serialize};
 
 extern crate extension_hub;
 
+mod archive;
+mod auth;
 mod axum_handlers;
+mod extension;
+mod extract;
 mod file;
+mod inventory;
+mod objects;
+mod release;
 mod server;
+mod signed_url;
 mod static_files;
+mod store;
+mod upload_session;
 
 #[derive(Parser, Debug, Serialize, Deserialize)]
 #[command(author, version, about)]
@@ -60,16 +70,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Config::try_parse().unwrap_or_default();
 
     let listener = tokio::net::TcpListener::bind(cli.addr).await.unwrap();
+    let auth = Arc::new(Authenticator::new(&cli.path_config.auth)?);
+    if !auth.is_enabled() {
+        tracing::warn!("No auth token configured, every endpoint is public");
+    }
     let greeter = MyExtensionHub::new(cli.path_config);
 
     let arc_greeter = Arc::new(greeter);
 
     let axum_routers = axum_handlers::router(arc_greeter.clone());
-    let svc = tonic::service::Routes::new(ExtensionHubServer::from_arc(arc_greeter.clone()));
+    let svc = tonic::service::Routes::new(InterceptedService::new(
+        ExtensionHubServer::from_arc(arc_greeter.clone()),
+        auth.interceptor(),
+    ));
     // let serve_dir: ServeDir = ServeDir::new(&arc_greeter.config.base_dir);
 
     let app = Router::new().merge(axum_routers).merge(svc.into_router());
 
-    axum::serve(listener, wrap_files_router(arc_greeter, app)).await?;
+    axum::serve(listener, wrap_files_router(arc_greeter, auth, app)?).await?;
     Ok(())
 }
@@ -0,0 +1,651 @@
+use futures::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::RwLock;
+use std::time::UNIX_EPOCH;
+use tempfile::TempPath;
+use tokio::io::AsyncWriteExt;
+use tonic::Status;
+use tracing::debug;
+use walkdir::WalkDir;
+
+use extension_hub::abi::extension_hub as abi;
+use extension_hub::error::HubError;
+
+use crate::extract::{normalize, ExtractPolicy};
+use crate::file::{path_is_valid, sibling_path};
+use crate::server::MyExtensionHub;
+
+extern crate extension_hub;
+
+/// One entry of a [`FileManifest`], paths are relative and `/` separated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FileEntry {
+    Dir {
+        path: String,
+    },
+    File {
+        path: String,
+        hash: String,
+        size: u64,
+        #[serde(default)]
+        executable: bool,
+    },
+    Symlink {
+        path: String,
+        target: String,
+    },
+}
+
+impl FileEntry {
+    pub fn path(&self) -> &str {
+        match self {
+            FileEntry::Dir { path }
+            | FileEntry::File { path, .. }
+            | FileEntry::Symlink { path, .. } => path,
+        }
+    }
+}
+
+/// The tree a tar unpacks to, or a client uploaded file by file, rebuilt
+/// from the object store on deploy.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileManifest {
+    pub entries: Vec<FileEntry>,
+}
+
+impl FileManifest {
+    /// Checks the tree a client sent the way `policy` checks a tar, and sorts
+    /// it so the same tree always has the same hash. Nothing may be placed
+    /// below a symlink.
+    pub fn from_abi(files: Vec<abi::FileEntry>, policy: &ExtractPolicy) -> Result<Self, HubError> {
+        if files.len() as u64 > policy.max_entries {
+            return Err(HubError::TooManyEntries(policy.max_entries));
+        }
+        let mut size = 0;
+        let mut entries = Vec::with_capacity(files.len());
+        for file in files {
+            let kind = file.kind();
+            let path = relative_path(&file.path)?.to_string_lossy().to_string();
+            let entry = match kind {
+                abi::FileKind::Dir => FileEntry::Dir { path },
+                abi::FileKind::File => {
+                    size += file.size;
+                    if size > policy.max_unpacked_size {
+                        return Err(HubError::ArchiveTooLarge(policy.max_unpacked_size));
+                    }
+                    FileEntry::File {
+                        path,
+                        hash: file.hash.to_ascii_lowercase(),
+                        size: file.size,
+                        executable: file.executable,
+                    }
+                }
+                abi::FileKind::Symlink => {
+                    let target = Path::new(&file.target);
+                    if target.is_absolute() {
+                        return Err(unsafe_entry(&path, "symlink to an absolute path"));
+                    }
+                    let parent = Path::new(&path).parent().unwrap_or(Path::new(""));
+                    if file.target.is_empty() || normalize(&parent.join(target)).is_none() {
+                        return Err(unsafe_entry(&path, "symlink points outside"));
+                    }
+                    FileEntry::Symlink {
+                        path,
+                        target: file.target,
+                    }
+                }
+            };
+            entries.push(entry);
+        }
+        entries.sort_by(|a, b| a.path().cmp(b.path()));
+        if let Some(pair) = entries
+            .windows(2)
+            .find(|pair| pair[0].path() == pair[1].path())
+        {
+            return Err(unsafe_entry(pair[0].path(), "listed twice"));
+        }
+        let links: HashSet<&str> = entries
+            .iter()
+            .filter(|e| matches!(e, FileEntry::Symlink { .. }))
+            .map(|e| e.path())
+            .collect();
+        for entry in &entries {
+            let below_link = Path::new(entry.path())
+                .ancestors()
+                .skip(1)
+                .any(|a| links.contains(a.to_string_lossy().as_ref()));
+            if below_link {
+                return Err(unsafe_entry(entry.path(), "path below a symlink"));
+            }
+        }
+        Ok(FileManifest { entries })
+    }
+
+    /// Names a tree uploaded file by file, like the hash of a tar.
+    pub fn hash(&self) -> Result<String, HubError> {
+        let bytes =
+            serde_json::to_vec(self).map_err(|e| HubError::OtherError(anyhow::anyhow!(e)))?;
+        Ok(blake3::hash(&bytes).to_hex().to_string())
+    }
+}
+
+fn unsafe_entry(path: &str, reason: &str) -> HubError {
+    HubError::UnsafeEntry(path.to_owned(), reason.to_owned())
+}
+
+/// Files kept once per content, named by their blake3 hash. Every stored
+/// tar has a manifest of the files it holds, and releases are built by
+/// hard linking the objects, so a file shared by many releases takes its
+/// space once.
+///
+/// ```text
+/// <root>/objects/<first 2 hex>/<hash>[.x]
+/// <root>/manifests/<tar_hash>.json
+/// ```
+///
+/// Executable files are kept apart, with a `.x` suffix, as links share
+/// their permissions.
+#[derive(Debug)]
+pub struct ObjectStore {
+    root: PathBuf,
+    /// Held for reading while a tree is added, for writing while pruning,
+    /// so no object is pruned before the manifest naming it is written
+    lock: RwLock<()>,
+}
+
+impl ObjectStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        ObjectStore {
+            root: root.into(),
+            lock: RwLock::new(()),
+        }
+    }
+
+    pub fn object_path(&self, hash: &str, executable: bool) -> Result<PathBuf, HubError> {
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(HubError::InvalidArgument(format!(
+                "invalid object hash '{}'",
+                hash
+            )));
+        }
+        let name = if executable {
+            format!("{}.x", hash)
+        } else {
+            hash.to_owned()
+        };
+        Ok(self.root.join("objects").join(&hash[..2]).join(name))
+    }
+
+    /// Whether the content of `hash` is stored, executable or not.
+    pub fn contains(&self, hash: &str) -> Result<bool, HubError> {
+        Ok(self.object_path(hash, false)?.exists() || self.object_path(hash, true)?.exists())
+    }
+
+    /// The object of `hash` with the permissions asked for, copied from the
+    /// other variant when only that one is stored.
+    fn ensure(&self, hash: &str, executable: bool) -> Result<PathBuf, HubError> {
+        let object = self.object_path(hash, executable)?;
+        if object.exists() {
+            return Ok(object);
+        }
+        let other = self.object_path(hash, !executable)?;
+        if !other.exists() {
+            return Err(HubError::InvalidArgument(format!(
+                "object '{}' was not uploaded",
+                hash
+            )));
+        }
+        let tmp_path = sibling_path(&object, "copy")?;
+        std::fs::copy(&other, &tmp_path)?;
+        let mode = if executable { 0o755 } else { 0o644 };
+        std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&tmp_path, &object)?;
+        Ok(object)
+    }
+
+    /// Stores an uploaded file whose content was checked to hash to `hash`.
+    pub fn insert(&self, path: &Path, hash: &str) -> Result<(), HubError> {
+        let _guard = self.lock.read().unwrap_or_else(|e| e.into_inner());
+        self.take_file(path, hash, false)
+    }
+
+    /// Saves `manifest` as the one of `tree_hash`, once every file it lists
+    /// is stored with the size it claims.
+    pub fn add_manifest(&self, tree_hash: &str, manifest: &FileManifest) -> Result<(), HubError> {
+        let _guard = self.lock.read().unwrap_or_else(|e| e.into_inner());
+        for entry in &manifest.entries {
+            let FileEntry::File {
+                path,
+                hash,
+                size,
+                executable,
+            } = entry
+            else {
+                continue;
+            };
+            let object = self.ensure(hash, *executable)?;
+            if std::fs::metadata(&object)?.len() != *size {
+                return Err(HubError::InvalidArgument(format!(
+                    "size of '{}' does not match its content",
+                    path
+                )));
+            }
+        }
+        self.write_manifest(tree_hash, manifest)
+    }
+
+    /// Hashes of the tars and trees that have a manifest.
+    pub fn manifest_hashes(&self) -> Result<Vec<String>, HubError> {
+        let manifests_dir = self.root.join("manifests");
+        if !manifests_dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut hashes = Vec::new();
+        for entry in std::fs::read_dir(&manifests_dir)? {
+            let file_name = entry?.file_name().to_string_lossy().to_string();
+            if let Some(hash) = file_name.strip_suffix(".json") {
+                hashes.push(hash.to_owned());
+            }
+        }
+        Ok(hashes)
+    }
+
+    fn manifest_path(&self, tar_hash: &str) -> Result<PathBuf, HubError> {
+        let file_name = format!("{}.json", tar_hash);
+        path_is_valid(&file_name)?;
+        Ok(self.root.join("manifests").join(file_name))
+    }
+
+    pub fn read_manifest(&self, tar_hash: &str) -> Result<Option<FileManifest>, HubError> {
+        let path = self.manifest_path(tar_hash)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bytes = std::fs::read(&path)?;
+        let manifest = serde_json::from_slice(&bytes).map_err(|e| {
+            HubError::ConfigureError(format!(
+                "invalid file manifest {}: {}",
+                path.to_string_lossy(),
+                e
+            ))
+        })?;
+        Ok(Some(manifest))
+    }
+
+    fn write_manifest(&self, tar_hash: &str, manifest: &FileManifest) -> Result<(), HubError> {
+        let path = self.manifest_path(tar_hash)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let bytes =
+            serde_json::to_vec(manifest).map_err(|e| HubError::OtherError(anyhow::anyhow!(e)))?;
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Moves the files of the unpacked tree `dir` into the store and saves
+    /// its manifest as the one of `tar_hash`. `dir` keeps only its dirs.
+    pub fn add_tree(&self, tar_hash: &str, dir: &Path) -> Result<FileManifest, HubError> {
+        let _guard = self.lock.read().unwrap_or_else(|e| e.into_inner());
+        let manifest = self.take_tree(dir)?;
+        self.write_manifest(tar_hash, &manifest)?;
+        Ok(manifest)
+    }
+
+    /// Like [`Self::add_tree`], saving the manifest as the one of its own
+    /// hash, which is returned with it.
+    pub fn add_tree_by_hash(&self, dir: &Path) -> Result<(String, FileManifest), HubError> {
+        let _guard = self.lock.read().unwrap_or_else(|e| e.into_inner());
+        let manifest = self.take_tree(dir)?;
+        let tree_hash = manifest.hash()?;
+        self.write_manifest(&tree_hash, &manifest)?;
+        Ok((tree_hash, manifest))
+    }
+
+    /// Moves the files of `dir` into the store and returns its manifest,
+    /// `dir` keeps only its dirs. The caller holds `lock` until the manifest
+    /// is written.
+    fn take_tree(&self, dir: &Path) -> Result<FileManifest, HubError> {
+        let mut manifest = FileManifest::default();
+        for entry in WalkDir::new(dir).min_depth(1).sort_by_file_name() {
+            let entry = entry.map_err(std::io::Error::from)?;
+            let path = entry
+                .path()
+                .strip_prefix(dir)
+                .map_err(|e| HubError::OtherError(e.into()))?
+                .to_string_lossy()
+                .to_string();
+            let file_type = entry.file_type();
+            if file_type.is_dir() {
+                manifest.entries.push(FileEntry::Dir { path });
+            } else if file_type.is_symlink() {
+                let target = std::fs::read_link(entry.path())?;
+                manifest.entries.push(FileEntry::Symlink {
+                    path,
+                    target: target.to_string_lossy().to_string(),
+                });
+            } else if file_type.is_file() {
+                let metadata = entry.metadata().map_err(std::io::Error::from)?;
+                let executable = metadata.permissions().mode() & 0o111 != 0;
+                let hash = hash_file(entry.path())?.to_hex().to_string();
+                self.take_file(entry.path(), &hash, executable)?;
+                manifest.entries.push(FileEntry::File {
+                    path,
+                    hash,
+                    size: metadata.len(),
+                    executable,
+                });
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Moves `path` into the store, or drops it when the object is there
+    /// already.
+    fn take_file(&self, path: &Path, hash: &str, executable: bool) -> Result<(), HubError> {
+        let object = self.object_path(hash, executable)?;
+        if object.exists() {
+            std::fs::remove_file(path)?;
+            return Ok(());
+        }
+        if let Some(parent) = object.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mode = if executable { 0o755 } else { 0o644 };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(path, &object)?;
+        Ok(())
+    }
+
+    /// Rebuilds the tree of `manifest` in `dst`, hard linking the objects or
+    /// copying them where linking is not possible, e.g. across file systems.
+    pub fn build(&self, manifest: &FileManifest, dst: &Path) -> Result<(), HubError> {
+        std::fs::create_dir_all(dst)?;
+        for entry in &manifest.entries {
+            match entry {
+                FileEntry::Dir { path } => {
+                    let path = dst.join(relative_path(path)?);
+                    std::fs::create_dir_all(&path)?;
+                    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+                }
+                FileEntry::File {
+                    path,
+                    hash,
+                    executable,
+                    ..
+                } => {
+                    let path = dst.join(relative_path(path)?);
+                    if let Some(parent) = path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    let object = self.object_path(hash, *executable)?;
+                    if std::fs::hard_link(&object, &path).is_err() {
+                        std::fs::copy(&object, &path)?;
+                    }
+                }
+                FileEntry::Symlink { path, target } => {
+                    let path = dst.join(relative_path(path)?);
+                    if let Some(parent) = path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    symlink(target, &path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the manifests of `dropped` and the objects no other manifest
+    /// refers to, returns the number of those objects and their size.
+    /// Objects stored since `keep_since`, unix seconds, are kept, their
+    /// manifest may still be on its way.
+    pub fn prune(
+        &self,
+        dropped: &HashSet<String>,
+        keep_since: u64,
+        dry_run: bool,
+    ) -> Result<(u64, u64), HubError> {
+        let _guard = self.lock.write().unwrap_or_else(|e| e.into_inner());
+        let mut referenced = HashSet::new();
+        for tar_hash in self.manifest_hashes()? {
+            if dropped.contains(&tar_hash) {
+                continue;
+            }
+            let Some(manifest) = self.read_manifest(&tar_hash)? else {
+                continue;
+            };
+            for entry in manifest.entries {
+                if let FileEntry::File {
+                    hash, executable, ..
+                } = entry
+                {
+                    referenced.insert(self.object_path(&hash, executable)?);
+                }
+            }
+        }
+
+        let (mut count, mut size) = (0, 0);
+        let objects_dir = self.root.join("objects");
+        if objects_dir.is_dir() {
+            for entry in WalkDir::new(&objects_dir).min_depth(2).max_depth(2) {
+                let entry = entry.map_err(std::io::Error::from)?;
+                if !entry.file_type().is_file() || referenced.contains(entry.path()) {
+                    continue;
+                }
+                let metadata = entry.metadata().map_err(std::io::Error::from)?;
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                if modified >= keep_since {
+                    continue;
+                }
+                count += 1;
+                size += metadata.len();
+                if !dry_run {
+                    std::fs::remove_file(entry.path())?;
+                }
+            }
+        }
+        if !dry_run {
+            for tar_hash in dropped {
+                let path = self.manifest_path(tar_hash)?;
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+        debug!("Prune {} objects, dry run: {}", count, dry_run);
+        Ok((count, size))
+    }
+}
+
+/// A manifest path as a relative path that stays inside the tree.
+fn relative_path(path: &str) -> Result<PathBuf, HubError> {
+    let relative = Path::new(path);
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return Err(HubError::InvalidPath(path.to_owned()));
+    }
+    Ok(relative.to_path_buf())
+}
+
+fn hash_file(path: &Path) -> std::io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+impl MyExtensionHub {
+    /// The manifest of a stored tar. Tars stored before the object store
+    /// existed are split into it the first time they are needed, on a
+    /// blocking thread as that unpacks the whole archive.
+    pub async fn tar_manifest(&self, tar_hash: &str) -> Result<FileManifest, HubError> {
+        if let Some(manifest) = self.objects.read_manifest(tar_hash)? {
+            return Ok(manifest);
+        }
+        let (tar_path, format) = self
+            .tar_file(tar_hash)
+            .filter(|(path, _)| path.exists())
+            .ok_or(HubError::TarNotExist(tar_hash.to_owned()))?;
+        let tmp_dir = self.config.tar_dir_path.join("__tmp__");
+        std::fs::create_dir_all(&tmp_dir)?;
+        let split_dir = sibling_path(tmp_dir.join(tar_hash), "split")?;
+        let policy = self.config.extract_policy.clone();
+        let objects = self.objects.clone();
+        let tar_hash = tar_hash.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let manifest = policy
+                .unpack_file(&tar_path, format, &split_dir)
+                .and_then(|_| objects.add_tree(&tar_hash, &split_dir));
+            let _ = std::fs::remove_dir_all(&split_dir);
+            debug!("Split tar {} into the object store", tar_hash);
+            manifest
+        })
+        .await
+        .map_err(|e| HubError::OtherError(e.into()))?
+    }
+}
+
+/// An object an `UploadObjects` call is sending, kept in a temp file that is
+/// removed unless it is stored.
+struct IncomingObject {
+    hash: String,
+    path: TempPath,
+    file: tokio::fs::File,
+    hasher: blake3::Hasher,
+    size: u64,
+}
+
+impl IncomingObject {
+    fn create(tmp_dir: &Path, hash: String) -> Result<Self, HubError> {
+        let (file, path) = tempfile::Builder::new()
+            .prefix(".object-")
+            .tempfile_in(tmp_dir)?
+            .into_parts();
+        Ok(IncomingObject {
+            hash: hash.to_ascii_lowercase(),
+            path,
+            file: tokio::fs::File::from_std(file),
+            hasher: blake3::Hasher::new(),
+            size: 0,
+        })
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), HubError> {
+        self.hasher.update(data);
+        self.file.write_all(data).await?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Stores the object once its content matches its hash.
+    async fn finish(mut self, objects: &ObjectStore) -> Result<u64, HubError> {
+        self.file.flush().await?;
+        let found = self.hasher.finalize().to_hex().to_string();
+        if found != self.hash {
+            return Err(HubError::HashNotMatch(self.hash, found));
+        }
+        objects.insert(&self.path, &self.hash)?;
+        Ok(self.size)
+    }
+}
+
+impl MyExtensionHub {
+    /// The files of a tree whose content is not stored yet.
+    pub fn plan_upload(&self, files: Vec<abi::FileEntry>) -> Result<abi::PlanUploadData, HubError> {
+        let manifest = FileManifest::from_abi(files, &self.config.extract_policy)?;
+        let mut data = abi::PlanUploadData::default();
+        let mut seen = HashSet::new();
+        for entry in &manifest.entries {
+            if let FileEntry::File { hash, size, .. } = entry {
+                if seen.insert(hash) && !self.objects.contains(hash)? {
+                    data.missing.push(hash.clone());
+                    data.missing_bytes += size;
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// Stores the objects of an `UploadObjects` call, each one only once its
+    /// content matches its hash. The call may send at most
+    /// `max_unpacked_size` bytes.
+    pub async fn receive_objects<S>(
+        &self,
+        mut stream: S,
+    ) -> Result<abi::UploadObjectsData, HubError>
+    where
+        S: Stream<Item = Result<abi::ObjectChunk, Status>> + Unpin,
+    {
+        let tmp_dir = self.config.tar_dir_path.join("__tmp__");
+        tokio::fs::create_dir_all(&tmp_dir).await?;
+        let max_size = self.config.extract_policy.max_unpacked_size;
+        let mut data = abi::UploadObjectsData::default();
+        let mut current: Option<IncomingObject> = None;
+        while let Some(chunk) = stream
+            .try_next()
+            .await
+            .map_err(|e| HubError::OtherError(e.into()))?
+        {
+            if !chunk.hash.is_empty() {
+                if let Some(object) = current.take() {
+                    data.size += object.finish(&self.objects).await?;
+                    data.objects += 1;
+                }
+                self.objects.object_path(&chunk.hash, false)?;
+                current = Some(IncomingObject::create(&tmp_dir, chunk.hash)?);
+            }
+            let Some(object) = current.as_mut() else {
+                return Err(HubError::InvalidArgument(
+                    "object data before its hash".to_owned(),
+                ));
+            };
+            if data.size + object.size + chunk.data.len() as u64 > max_size {
+                return Err(HubError::ArchiveTooLarge(max_size));
+            }
+            object.write(&chunk.data).await?;
+        }
+        if let Some(object) = current.take() {
+            data.size += object.finish(&self.objects).await?;
+            data.objects += 1;
+        }
+        debug!("Received {} objects, {} bytes", data.objects, data.size);
+        Ok(data)
+    }
+
+    /// Deploys a tree uploaded file by file as a new release of
+    /// `target_dir`, named by the hash of the tree.
+    pub async fn commit_upload(
+        &self,
+        request: abi::CommitUploadRequest,
+    ) -> Result<abi::Release, HubError> {
+        let abi::CommitUploadRequest {
+            target_dir,
+            files,
+            overwrite,
+        } = request;
+        let path = self.target_dir_path(&target_dir)?;
+        let manifest = FileManifest::from_abi(files, &self.config.extract_policy)?;
+        let tree_hash = manifest.hash()?;
+        let _guard = self.lock_dir(&target_dir).await;
+        if path.exists() && !overwrite.unwrap_or(false) {
+            return Err(HubError::DirHasExist(target_dir));
+        };
+        self.objects.add_manifest(&tree_hash, &manifest)?;
+        let release = self
+            .deploy_release(&tree_hash, &target_dir, manifest)
+            .await?;
+        Ok(release.to_abi(true))
+    }
+}
@@ -0,0 +1,301 @@
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use tokio::sync::OwnedMutexGuard;
+use tracing::debug;
+
+use extension_hub::abi::extension_hub as abi;
+use extension_hub::error::HubError;
+
+use crate::extension::ExtensionManifest;
+use crate::file::{dir_size, sibling_path};
+use crate::objects::FileManifest;
+use crate::server::MyExtensionHub;
+use crate::store::unix_now;
+
+extern crate extension_hub;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Release {
+    pub id: String,
+    pub tar_hash: String,
+    pub created_at: u64,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub manifest: Option<ExtensionManifest>,
+}
+
+impl Release {
+    pub fn to_abi(&self, current: bool) -> abi::Release {
+        abi::Release {
+            id: self.id.clone(),
+            tar_hash: self.tar_hash.clone(),
+            created_at: self.created_at,
+            current,
+        }
+    }
+}
+
+/// The releases kept for one target dir, oldest first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReleaseHistory {
+    #[serde(default)]
+    pub current: Option<String>,
+    /// When `current` was switched to
+    #[serde(default)]
+    pub deployed_at: u64,
+    #[serde(default)]
+    pub releases: Vec<Release>,
+}
+
+impl ReleaseHistory {
+    pub fn current_release(&self) -> Option<&Release> {
+        let current = self.current.as_ref()?;
+        self.releases.iter().find(|r| &r.id == current)
+    }
+
+    /// The newest release of `tar_hash`.
+    pub fn find(&self, tar_hash: &str) -> Option<&Release> {
+        self.releases.iter().rev().find(|r| r.tar_hash == tar_hash)
+    }
+
+    /// The release deployed right before the current one.
+    pub fn previous(&self) -> Option<&Release> {
+        let current = self.current.as_ref()?;
+        let index = self.releases.iter().position(|r| &r.id == current)?;
+        index.checked_sub(1).map(|i| &self.releases[i])
+    }
+
+    /// Drops the oldest releases beyond `keep`, the current release always
+    /// stays.
+    pub fn prune(&mut self, keep: usize) -> Vec<Release> {
+        let mut pruned = Vec::new();
+        while self.releases.len() > keep.max(1) {
+            let Some(index) = self
+                .releases
+                .iter()
+                .position(|r| Some(&r.id) != self.current.as_ref())
+            else {
+                break;
+            };
+            pruned.push(self.releases.remove(index));
+        }
+        pruned
+    }
+}
+
+/// Deployments are kept as releases under `<releases_path>/<item_dir>/<id>`,
+/// `<base_dir>/<item_dir>` is a symlink to the current one. Switching
+/// releases replaces the symlink with a rename, so it is atomic.
+impl MyExtensionHub {
+    /// Serializes deployments and rollbacks of one target dir.
+    pub async fn lock_dir(&self, item_dir: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .context
+            .dir_locks
+            .entry(item_dir.to_owned())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    pub fn release_dir(&self, item_dir: &str) -> PathBuf {
+        self.config.releases_path().join(item_dir)
+    }
+
+    /// Builds the tree of `manifest` from the object store, on a blocking
+    /// thread, as a new release of `item_dir` and makes it current. Nothing
+    /// that is served changes unless the whole tree was built.
+    pub async fn deploy_release(
+        &self,
+        tar_hash: &str,
+        item_dir: &str,
+        manifest: FileManifest,
+    ) -> Result<Release, HubError> {
+        let staging = self.release_staging(item_dir)?;
+        let objects = self.objects.clone();
+        let built = {
+            let staging = staging.clone();
+            tokio::task::spawn_blocking(move || objects.build(&manifest, &staging))
+                .await
+                .map_err(|e| HubError::OtherError(e.into()))
+                .and_then(|built| built)
+        };
+        if let Err(e) = built {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+        self.deploy_staged(tar_hash, item_dir, &staging)
+    }
+
+    /// A path next to the releases of `item_dir` to build a new one in.
+    pub fn release_staging(&self, item_dir: &str) -> Result<PathBuf, HubError> {
+        let release_root = self.release_dir(item_dir);
+        std::fs::create_dir_all(&release_root)?;
+        sibling_path(release_root.join("release"), "staging")
+    }
+
+    /// Moves the dir built in `staging` into place as a new release of
+    /// `item_dir` and makes it current, `staging` is removed on failure.
+    pub fn deploy_staged(
+        &self,
+        tar_hash: &str,
+        item_dir: &str,
+        staging: &Path,
+    ) -> Result<Release, HubError> {
+        let release_root = self.release_dir(item_dir);
+        let mut release = Release {
+            id: self.new_release_id(item_dir, tar_hash),
+            tar_hash: tar_hash.to_owned(),
+            created_at: unix_now(),
+            size: 0,
+            manifest: None,
+        };
+        let release_path = release_root.join(&release.id);
+        if let Err(e) = std::fs::rename(staging, &release_path) {
+            let _ = std::fs::remove_dir_all(staging);
+            return Err(e.into());
+        }
+        release.size = dir_size(&release_path);
+        release.manifest = ExtensionManifest::read(&release_path);
+        self.adopt_legacy_dir(item_dir)?;
+        self.context
+            .releases
+            .entry(item_dir.to_owned())
+            .or_default()
+            .releases
+            .push(release.clone());
+        self.switch_release(item_dir, &release)?;
+        Ok(release)
+    }
+
+    /// Points `item_dir` back at an earlier release, the newest one of
+    /// `tar_hash` or, when `tar_hash` is empty, the one before the current.
+    pub fn rollback_release(&self, item_dir: &str, tar_hash: &str) -> Result<Release, HubError> {
+        let release = {
+            let history = self
+                .context
+                .releases
+                .get(item_dir)
+                .ok_or(HubError::DirNotExist(item_dir.to_owned()))?;
+            let release = if tar_hash.is_empty() {
+                history.previous()
+            } else {
+                history.find(tar_hash)
+            };
+            release.cloned().ok_or(HubError::ReleaseNotExist(
+                item_dir.to_owned(),
+                tar_hash.to_owned(),
+            ))?
+        };
+        self.switch_release(item_dir, &release)?;
+        Ok(release)
+    }
+
+    pub fn list_releases(&self, item_dir: &str) -> Result<Vec<abi::Release>, HubError> {
+        let history = self
+            .context
+            .releases
+            .get(item_dir)
+            .ok_or(HubError::DirNotExist(item_dir.to_owned()))?;
+        Ok(history
+            .releases
+            .iter()
+            .rev()
+            .map(|r| r.to_abi(history.current.as_ref() == Some(&r.id)))
+            .collect())
+    }
+
+    fn switch_release(&self, item_dir: &str, release: &Release) -> Result<(), HubError> {
+        let link = self.config.base_dir.join(item_dir);
+        let target = self.release_dir(item_dir).join(&release.id);
+        // Relative links keep working when base dir is mounted elsewhere.
+        let target = target
+            .strip_prefix(&self.config.base_dir)
+            .map(|p| p.to_path_buf())
+            .unwrap_or(target);
+        let tmp_link = sibling_path(&link, "link")?;
+        symlink(&target, &tmp_link)?;
+        if let Err(e) = std::fs::rename(&tmp_link, &link) {
+            let _ = std::fs::remove_file(&tmp_link);
+            return Err(e.into());
+        }
+        debug!("Switch {} to release {}", item_dir, release.id);
+
+        let pruned = {
+            let mut history = self
+                .context
+                .releases
+                .entry(item_dir.to_owned())
+                .or_default();
+            history.current = Some(release.id.clone());
+            history.deployed_at = unix_now();
+            history.prune(self.config.keep_releases)
+        };
+        for old in pruned {
+            let path = self.release_dir(item_dir).join(&old.id);
+            if let Err(e) = std::fs::remove_dir_all(&path) {
+                tracing::warn!("Failed to remove release {:?}: {:?}", path, e);
+            }
+        }
+        for set in self.context.item_dir_map.iter() {
+            set.remove(item_dir);
+        }
+        self.context.item_dir_map.retain(|_, set| !set.is_empty());
+        if release.tar_hash.is_empty() {
+            return self.persist();
+        }
+        self.add_tar_dir(&release.tar_hash, item_dir)
+    }
+
+    /// Moves a dir deployed before releases existed into the release
+    /// history, so the symlink can take its place.
+    fn adopt_legacy_dir(&self, item_dir: &str) -> Result<(), HubError> {
+        let path = self.config.base_dir.join(item_dir);
+        let Ok(metadata) = std::fs::symlink_metadata(&path) else {
+            return Ok(());
+        };
+        if !metadata.is_dir() {
+            return Ok(());
+        }
+        let tar_hash = self
+            .context
+            .item_dir_map
+            .iter()
+            .find(|set| set.contains(item_dir))
+            .map(|set| set.key().clone())
+            .unwrap_or_default();
+        let mut release = Release {
+            id: format!("{}-legacy", unix_now()),
+            tar_hash,
+            created_at: unix_now(),
+            size: 0,
+            manifest: None,
+        };
+        let release_path = self.release_dir(item_dir).join(&release.id);
+        std::fs::rename(&path, &release_path)?;
+        release.size = dir_size(&release_path);
+        release.manifest = ExtensionManifest::read(&release_path);
+        self.context
+            .releases
+            .entry(item_dir.to_owned())
+            .or_default()
+            .releases
+            .insert(0, release);
+        Ok(())
+    }
+
+    fn new_release_id(&self, item_dir: &str, tar_hash: &str) -> String {
+        let short_hash = tar_hash.get(..12).unwrap_or(tar_hash);
+        let base = format!("{}-{}", unix_now(), short_hash);
+        let release_root = self.release_dir(item_dir);
+        let mut id = base.clone();
+        let mut n = 1;
+        while release_root.join(&id).exists() {
+            id = format!("{}-{}", base, n);
+            n += 1;
+        }
+        id
+    }
+}
@@ -1,25 +1,36 @@
 use anyhow::Result;
 use clap::Parser;
 use dashmap::{DashMap, DashSet};
-use flate2::read::GzDecoder;
 use extension_hub::error::HubError;
 use extension_hub::text_replace;
 // use extension_hub::macros::AppError;
 use extension_hub::{abi::extension_hub as abi, abi::extension_hub::extension_hub_server::ExtensionHub};
+use bytes::Bytes;
+use futures::{Stream, StreamExt, TryStreamExt};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use std::io::Read;
-use std::io::Write;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::result::Result::Ok;
 use std::sync::Arc;
-use tar::Archive;
-use tokio::time::{sleep, Duration};
-use tonic::{Request, Response, Status};
+use tokio::time::Duration;
+use tonic::{Request, Response, Status, Streaming};
 use tracing::debug;
 
-use crate::file::path_is_valid;
+use crate::archive::ArchiveFormat;
+use crate::auth::{authenticated, require, AuthConfig, Grant, Scope};
+use crate::extract::ExtractPolicy;
+use crate::file::{dir_size, path_is_valid, stream_to_file, ReceivedFile, MAX_UPLOAD_SIZE};
+use crate::inventory::TarRecord;
+use crate::objects::ObjectStore;
+use crate::release::ReleaseHistory;
+use crate::signed_url::{
+    url_id, url_ttl, UrlClaims, UrlOperation, UrlSigner, UrlUses, DOWNLOAD_URL_TTL, UPLOAD_URL_TTL,
+};
+use crate::static_files::StaticConfig;
+use crate::store::{unix_now, Manifest, MetaStore};
+use crate::upload_session::UploadSession;
 
 extern crate extension_hub;
 
@@ -29,6 +40,79 @@ pub struct MyExtensionHubConfig {
     pub base_dir: PathBuf,
     #[arg(short, long)]
     pub tar_dir_path: PathBuf,
+    /// Where the tar registry is persisted, defaults to `<tar_dir_path>/__meta__.json`
+    #[arg(long)]
+    #[serde(default)]
+    pub meta_path: Option<PathBuf>,
+    /// Seconds an idle resumable upload session is kept
+    #[arg(long, default_value_t = default_upload_session_idle_secs())]
+    #[serde(default = "default_upload_session_idle_secs")]
+    pub upload_session_idle_secs: u64,
+    #[command(flatten)]
+    #[serde(default)]
+    pub extract_policy: ExtractPolicy,
+    /// Where releases are unpacked, defaults to `<base_dir>/__releases`
+    #[arg(long)]
+    #[serde(default)]
+    pub releases_path: Option<PathBuf>,
+    /// Number of releases kept per target dir
+    #[arg(long, default_value_t = default_keep_releases())]
+    #[serde(default = "default_keep_releases")]
+    pub keep_releases: usize,
+    #[command(flatten)]
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[command(flatten)]
+    #[serde(default)]
+    pub static_files: StaticConfig,
+    /// Secret signing upload and download urls, share it between instances
+    /// behind one address. A random one is used when unset
+    #[arg(long)]
+    #[serde(default)]
+    pub url_secret: Option<String>,
+    /// Longest lifetime a client may request for an upload url, in seconds
+    #[arg(long, default_value_t = default_max_upload_url_ttl_secs())]
+    #[serde(default = "default_max_upload_url_ttl_secs")]
+    pub max_upload_url_ttl_secs: u64,
+    /// Longest lifetime a client may request for a download url, in seconds
+    #[arg(long, default_value_t = default_max_download_url_ttl_secs())]
+    #[serde(default = "default_max_download_url_ttl_secs")]
+    pub max_download_url_ttl_secs: u64,
+    /// Where `ReplaceText` keeps what it overwrote, defaults to
+    /// `<base_dir>/__replace_journal`
+    #[arg(long)]
+    #[serde(default)]
+    pub replace_journal_path: Option<PathBuf>,
+    /// Number of `ReplaceText` runs that can be undone
+    #[arg(long, default_value_t = default_keep_replace_journals())]
+    #[serde(default = "default_keep_replace_journals")]
+    pub keep_replace_journals: usize,
+    /// Where the files of uploaded tars are kept by hash, defaults to
+    /// `<tar_dir_path>/__store__`. Releases hard link them, so keep it on
+    /// the file system of `base_dir`
+    #[arg(long)]
+    #[serde(default)]
+    pub objects_path: Option<PathBuf>,
+}
+
+fn default_keep_replace_journals() -> usize {
+    10
+}
+
+fn default_max_upload_url_ttl_secs() -> u64 {
+    60 * 60
+}
+
+fn default_max_download_url_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_keep_releases() -> usize {
+    3
+}
+
+fn default_upload_session_idle_secs() -> u64 {
+    30 * 60
 }
 
 impl MyExtensionHubConfig {
@@ -36,8 +120,45 @@ impl MyExtensionHubConfig {
         MyExtensionHubConfig {
             base_dir: base_dir.into(),
             tar_dir_path: tar_dir_path.into(),
+            meta_path: None,
+            upload_session_idle_secs: default_upload_session_idle_secs(),
+            extract_policy: ExtractPolicy::default(),
+            releases_path: None,
+            keep_releases: default_keep_releases(),
+            auth: AuthConfig::default(),
+            static_files: StaticConfig::default(),
+            url_secret: None,
+            max_upload_url_ttl_secs: default_max_upload_url_ttl_secs(),
+            max_download_url_ttl_secs: default_max_download_url_ttl_secs(),
+            replace_journal_path: None,
+            keep_replace_journals: default_keep_replace_journals(),
+            objects_path: None,
         }
     }
+
+    pub fn meta_path(&self) -> PathBuf {
+        self.meta_path
+            .clone()
+            .unwrap_or_else(|| self.tar_dir_path.join("__meta__.json"))
+    }
+
+    pub fn releases_path(&self) -> PathBuf {
+        self.releases_path
+            .clone()
+            .unwrap_or_else(|| self.base_dir.join("__releases"))
+    }
+
+    pub fn replace_journal_path(&self) -> PathBuf {
+        self.replace_journal_path
+            .clone()
+            .unwrap_or_else(|| self.base_dir.join("__replace_journal"))
+    }
+
+    pub fn objects_path(&self) -> PathBuf {
+        self.objects_path
+            .clone()
+            .unwrap_or_else(|| self.tar_dir_path.join("__store__"))
+    }
 }
 
 impl Default for MyExtensionHubConfig {
@@ -46,36 +167,204 @@ impl Default for MyExtensionHubConfig {
         MyExtensionHubConfig {
             base_dir: path.clone(),
             tar_dir_path: path.join("__tar"),
+            meta_path: None,
+            upload_session_idle_secs: default_upload_session_idle_secs(),
+            extract_policy: ExtractPolicy::default(),
+            releases_path: None,
+            keep_releases: default_keep_releases(),
+            auth: AuthConfig::default(),
+            static_files: StaticConfig::default(),
+            url_secret: None,
+            max_upload_url_ttl_secs: default_max_upload_url_ttl_secs(),
+            max_download_url_ttl_secs: default_max_download_url_ttl_secs(),
+            replace_journal_path: None,
+            keep_replace_journals: default_keep_replace_journals(),
+            objects_path: None,
         }
     }
 }
 
 #[derive(Debug, Default)]
 pub struct MyExtensionHubContext {
-    pub tar_set: DashSet<String>,
+    pub tars: DashMap<String, TarRecord>,
     pub item_dir_map: DashMap<String, DashSet<String>>,
-    pub upload_path_map: Arc<DashMap<String, abi::UploadTarRequest>>,
-    pub download_path_map: Arc<DashMap<String, abi::DownloadTarRequest>>,
+    pub upload_sessions: DashMap<String, Arc<tokio::sync::Mutex<UploadSession>>>,
+    pub releases: DashMap<String, ReleaseHistory>,
+    pub dir_locks: DashMap<String, Arc<tokio::sync::Mutex<()>>>,
+    pub url_uses: DashMap<String, UrlUses>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct MyExtensionHub {
     pub config: MyExtensionHubConfig,
     pub context: MyExtensionHubContext,
+    pub store: MetaStore,
+    pub objects: Arc<ObjectStore>,
+    pub url_signer: UrlSigner,
 }
 
 impl MyExtensionHub {
     pub fn new(config: MyExtensionHubConfig) -> Self {
-        MyExtensionHub {
+        let store = MetaStore::new(config.meta_path());
+        let objects = Arc::new(ObjectStore::new(config.objects_path()));
+        let url_signer = match &config.url_secret {
+            Some(secret) => UrlSigner::new(secret),
+            None => {
+                tracing::warn!(
+                    "No url secret configured, upload and download urls only work until restart"
+                );
+                UrlSigner::random()
+            }
+        };
+        let hub = MyExtensionHub {
             config,
             context: MyExtensionHubContext::default(),
+            store,
+            objects,
+            url_signer,
+        };
+        if let Err(e) = hub.restore() {
+            tracing::error!(
+                "Failed to restore registry from {:?}: {:?}",
+                hub.store.path(),
+                e
+            );
+        }
+        hub
+    }
+
+    /// Reloads the persisted registry and reconciles it against the disk:
+    /// entries whose tarball or directory is gone are dropped, and tarballs
+    /// found in `tar_dir_path` but missing from the manifest are registered.
+    fn restore(&self) -> Result<(), HubError> {
+        let Manifest {
+            tars,
+            dirs,
+            releases,
+            url_uses,
+        } = self.store.load()?;
+        if self.config.tar_dir_path.is_dir() {
+            for entry in std::fs::read_dir(&self.config.tar_dir_path)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_file() {
+                    continue;
+                }
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                let Some((tar_hash, format)) = ArchiveFormat::split_file_name(&file_name) else {
+                    continue;
+                };
+                let metadata = entry.metadata()?;
+                // Manifests written before tars had records only list hashes.
+                let record = match tars.get(tar_hash) {
+                    Some(record) if record.uploaded_at > 0 => TarRecord {
+                        size: metadata.len(),
+                        format,
+                        ..record.clone()
+                    },
+                    _ => TarRecord::from_metadata(&metadata, format),
+                };
+                self.context.tars.insert(tar_hash.to_owned(), record);
+            }
+        }
+        for (tar_hash, item_dirs) in dirs {
+            if !self.context.tars.contains_key(&tar_hash) {
+                continue;
+            }
+            for item_dir in item_dirs {
+                if path_is_valid(&item_dir).is_ok() && self.config.base_dir.join(&item_dir).is_dir()
+                {
+                    self.add_tar_dir_entry(&tar_hash, &item_dir);
+                }
+            }
+        }
+        for (item_dir, mut history) in releases {
+            let release_dir = self.release_dir(&item_dir);
+            history
+                .releases
+                .retain(|r| release_dir.join(&r.id).is_dir());
+            if history.current_release().is_none() {
+                history.current = None;
+            }
+            if !history.releases.is_empty() {
+                self.context.releases.insert(item_dir, history);
+            }
         }
+        let now = unix_now();
+        for (id, uses) in url_uses {
+            if uses.expires_at >= now {
+                self.context.url_uses.insert(id, uses);
+            }
+        }
+        debug!(
+            "Restored {} tars from {:?}",
+            self.context.tars.len(),
+            self.store.path()
+        );
+        self.persist()
     }
+
+    pub fn persist(&self) -> Result<(), HubError> {
+        self.store.save_with(|| Manifest {
+            tars: self
+                .context
+                .tars
+                .iter()
+                .map(|e| (e.key().clone(), e.value().clone()))
+                .collect(),
+            dirs: self
+                .context
+                .item_dir_map
+                .iter()
+                .map(|e| {
+                    (
+                        e.key().clone(),
+                        e.value().iter().map(|d| d.key().clone()).collect(),
+                    )
+                })
+                .collect(),
+            releases: self
+                .context
+                .releases
+                .iter()
+                .map(|e| (e.key().clone(), e.value().clone()))
+                .collect(),
+            url_uses: self
+                .context
+                .url_uses
+                .iter()
+                .filter(|e| e.expires_at >= unix_now())
+                .map(|e| (e.key().clone(), e.value().clone()))
+                .collect(),
+        })
+    }
+
+    pub fn tar_path(&self, tar_hash: &str) -> Option<PathBuf> {
+        self.tar_file(tar_hash).map(|(path, _)| path)
+    }
+
+    /// Where a tar is stored and how it is packed. The format is the
+    /// recorded one, or for a tar not registered yet the one found on disk.
+    pub fn tar_file(&self, tar_hash: &str) -> Option<(PathBuf, ArchiveFormat)> {
+        path_is_valid(tar_hash).ok()?;
+        let path = |format: ArchiveFormat| {
+            self.config
+                .tar_dir_path
+                .join(format!("{}{}", tar_hash, format.suffix()))
+        };
+        let format = match self.context.tars.get(tar_hash) {
+            Some(record) => record.format,
+            None => ArchiveFormat::ALL
+                .into_iter()
+                .find(|format| path(*format).is_file())?,
+        };
+        Some((path(format), format))
+    }
+
     pub fn get_tar_hash(&self, tar_hash: &str) -> Result<String, HubError> {
-        if self.context.tar_set.contains(tar_hash) {
-            let tar_file = format!("{}.tar.gz", tar_hash);
-            path_is_valid(&tar_file)?;
-            let path = self.config.tar_dir_path.join(&tar_file);
+        if self.context.tars.contains_key(tar_hash) {
+            let path = self
+                .tar_path(tar_hash)
+                .ok_or(HubError::InvalidPath(tar_hash.to_owned()))?;
             if path.exists() {
                 return Ok(tar_hash.to_owned());
             };
@@ -104,75 +393,99 @@ impl MyExtensionHub {
         Err(HubError::FileNotExist(item_dir.to_owned()))
     }
 
+    /// Returns the url and when it expires.
     pub fn generate_upload_url(
         &self,
         upload_tar_request: abi::UploadTarRequest,
-    ) -> Result<String, HubError> {
-        let upload_path: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(64)
-            .map(char::from)
-            .collect();
-
-        self.context
-            .upload_path_map
-            .insert(upload_path.clone(), upload_tar_request);
-        let path_clone = upload_path.clone();
-        let upload_path_map = self.context.upload_path_map.clone();
-        tokio::task::spawn(async move {
-            let sleep_time = Duration::from_secs(30);
-            sleep(sleep_time).await;
-            upload_path_map.remove(&path_clone);
-        });
-        Ok(upload_path)
+    ) -> Result<(String, u64), HubError> {
+        let ttl = url_ttl(
+            upload_tar_request.ttl_secs,
+            UPLOAD_URL_TTL,
+            self.config.max_upload_url_ttl_secs,
+        );
+        let claims = UrlClaims::upload(&upload_tar_request, ttl);
+        Ok((self.url_signer.sign(&claims)?, claims.expires_at))
     }
 
+    /// Returns the url and when it expires.
     pub fn generate_download_url(
         &self,
         download_tar_request: abi::DownloadTarRequest,
-    ) -> Result<String, HubError> {
-        let download_path: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(64)
-            .map(char::from)
-            .collect();
+    ) -> Result<(String, u64), HubError> {
+        if download_tar_request.max_uses == Some(0) {
+            return Err(HubError::InvalidArgument(
+                "max uses must be positive".to_owned(),
+            ));
+        }
+        let ttl = url_ttl(
+            download_tar_request.ttl_secs,
+            DOWNLOAD_URL_TTL,
+            self.config.max_download_url_ttl_secs,
+        );
+        let claims = UrlClaims::download(&download_tar_request, ttl);
+        Ok((self.url_signer.sign(&claims)?, claims.expires_at))
+    }
 
-        self.context
-            .download_path_map
-            .insert(download_path.clone(), download_tar_request);
-        let path_clone = download_path.clone();
-        let download_path_map = self.context.download_path_map.clone();
-        tokio::task::spawn(async move {
-            let sleep_time = Duration::from_mins(30);
-            sleep(sleep_time).await;
-            download_path_map.remove(&path_clone);
-        });
-        Ok(download_path)
+    /// The upload request a url from `generate_upload_url` was signed for.
+    pub fn verify_upload_url(&self, url: &str) -> Result<abi::UploadTarRequest, HubError> {
+        let claims = self.url_signer.verify(url, UrlOperation::Upload)?;
+        Ok(claims.upload_request())
     }
 
+    /// Verifies a download url and counts the use against its `max_uses`.
+    /// Counts are kept by this instance only, the caller persists them.
+    pub fn use_download_url(&self, url: &str) -> Result<UrlClaims, HubError> {
+        let claims = self.url_signer.verify(url, UrlOperation::Download)?;
+        let Some(max_uses) = claims.max_uses else {
+            return Ok(claims);
+        };
+        let mut entry = self
+            .context
+            .url_uses
+            .entry(url_id(url).to_owned())
+            .or_insert(UrlUses {
+                uses: 0,
+                expires_at: claims.expires_at,
+            });
+        if entry.uses >= max_uses {
+            return Err(HubError::InvalidUrl("used up".to_owned()));
+        }
+        entry.uses += 1;
+        Ok(claims)
+    }
+
+    /// Verifies a download url without counting a use, for `HEAD` requests.
+    pub fn check_download_url(&self, url: &str) -> Result<UrlClaims, HubError> {
+        let claims = self.url_signer.verify(url, UrlOperation::Download)?;
+        if let Some(max_uses) = claims.max_uses {
+            let used_up = self
+                .context
+                .url_uses
+                .get(url_id(url))
+                .is_some_and(|entry| entry.uses >= max_uses);
+            if used_up {
+                return Err(HubError::InvalidUrl("used up".to_owned()));
+            }
+        }
+        Ok(claims)
+    }
+
+    /// Untars as a new release of `item_dir`, see [`MyExtensionHub::deploy_release`].
     pub async fn un_tar_to_dir(
         &self,
         tar_hash: &str,
         item_dir: &str,
         overwrite: bool,
     ) -> Result<(), HubError> {
-        path_is_valid(item_dir)?;
-        let path = self.config.base_dir.join(item_dir);
+        let path = self.target_dir_path(item_dir)?;
+        let _guard = self.lock_dir(item_dir).await;
         if path.exists() && !overwrite {
             return Err(HubError::DirHasExist(item_dir.to_owned()));
         };
-        let mut file_name = self.get_tar_hash(tar_hash)?;
-        file_name.push_str(".tar.gz");
-        path_is_valid(&file_name)?;
-        let tar_gz = std::fs::File::open(self.config.tar_dir_path.join(&file_name))?;
-
-        let tar: GzDecoder<_> = GzDecoder::new(tar_gz);
-        let mut archive = Archive::new(tar);
-        if path.exists() && overwrite {
-            std::fs::remove_dir_all(&path)?;
-        };
-        archive.unpack(path)?;
-        self.add_tar_dir(tar_hash, item_dir);
+        self.get_tar_hash(tar_hash)?;
+        let manifest = self.tar_manifest(tar_hash).await?;
+        self.touch_tar(tar_hash);
+        self.deploy_release(tar_hash, item_dir, manifest).await?;
         Ok(())
     }
 
@@ -185,30 +498,218 @@ impl MyExtensionHub {
             old_text,
             new_text,
             suffix,
+            rules,
+            include,
+            exclude,
+            dry_run,
+            encoding,
+            keep_metadata,
+            transactional,
+            output_dir,
         } = request;
 
-        let source_path = self.config.base_dir.join(target_dir);
-        let output_path = source_path.clone();
+        let mut replace_rules = Vec::new();
+        if !old_text.is_empty() {
+            replace_rules.push(text_replace::Rule::literal(old_text, new_text));
+        }
+        for rule in rules {
+            let mode = match rule.mode() {
+                abi::ReplaceMode::Literal => text_replace::RuleMode::Literal,
+                abi::ReplaceMode::Regex => text_replace::RuleMode::Regex,
+                abi::ReplaceMode::WholeWord => text_replace::RuleMode::WholeWord,
+            };
+            replace_rules.push(text_replace::Rule {
+                pattern: rule.pattern,
+                replacement: rule.replacement,
+                mode,
+            });
+        }
+        if replace_rules.is_empty() {
+            return Err(HubError::InvalidArgument("no replace rule".to_owned()));
+        }
+        for rule in &replace_rules {
+            rule.compile()
+                .map_err(|e| HubError::InvalidArgument(format!("{:#}", e)))?;
+        }
+
+        let encoding = match encoding {
+            Some(label) => encoding_rs::Encoding::for_label(label.as_bytes())
+                .ok_or_else(|| HubError::InvalidArgument(format!("unknown encoding {}", label)))?,
+            None => encoding_rs::UTF_8,
+        };
+
+        let source_path = self.target_dir_path(&target_dir)?;
+        let output_path = match output_dir.filter(|dir| !dir.is_empty()) {
+            Some(output_dir) => {
+                let output_path = self.target_dir_path(&output_dir)?;
+                if output_dir == target_dir {
+                    return Err(HubError::InvalidPath(output_dir));
+                }
+                output_path
+            }
+            None => source_path.clone(),
+        };
         Ok(text_replace::Setting {
-            old_web_prefix: old_text,
-            new_web_prefix: new_text,
+            rules: replace_rules,
+            include_path: Some(include),
+            exclude_path: Some(exclude),
             source_path: source_path.to_string_lossy().to_string(),
             output_path: output_path.to_string_lossy().to_string(),
-            exclude_path: None,
             file_types: Some(suffix),
+            dry_run: dry_run.unwrap_or(false),
+            journal_path: None,
+            encoding,
+            keep_metadata: keep_metadata.unwrap_or(true),
+            transactional: transactional.unwrap_or(false),
         })
     }
 
-    pub fn text_replace_by_request(
+    pub async fn text_replace_by_request(
         &self,
         request: abi::ReplaceTextRequest,
-    ) -> Result<(), HubError> {
-        let config = self.text_replace_request_to_setting(request)?;
-        Ok(config.text_replace()?)
+    ) -> Result<abi::ReplaceTextData, HubError> {
+        let output_dir = request.output_dir.clone().filter(|dir| !dir.is_empty());
+        let target_dir = request.target_dir.clone();
+        let mut config = self.text_replace_request_to_setting(request)?;
+        let (report, run_id) = match output_dir {
+            Some(output_dir) => {
+                let report = self.text_replace_into_release(config, &output_dir).await?;
+                (report, String::new())
+            }
+            None => {
+                // the release the dir links to must not switch mid-run
+                let _guard = if config.dry_run {
+                    None
+                } else {
+                    Some(self.lock_dir(&target_dir).await)
+                };
+                let suffix: String = thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(8)
+                    .map(char::from)
+                    .collect();
+                let run_id = format!("{}-{}", unix_now(), suffix);
+                config.journal_path = Some(self.config.replace_journal_path().join(&run_id));
+                let report = config.text_replace()?;
+                if report.journaled {
+                    self.prune_replace_journals();
+                    (report, run_id)
+                } else {
+                    (report, String::new())
+                }
+            }
+        };
+        Ok(abi::ReplaceTextData {
+            files: report
+                .changes
+                .into_iter()
+                .map(|change| abi::ReplacedFile {
+                    path: change.path,
+                    replacements: change.replacements as u64,
+                    diff: change.diff,
+                })
+                .collect(),
+            files_scanned: report.files_scanned as u64,
+            skipped_by_type: report.skipped_by_type as u64,
+            skipped_by_exclusion: report.skipped_by_exclusion as u64,
+            skipped_binary: report.skipped_binary as u64,
+            files_changed: report.files_changed as u64,
+            replacements: report.replacements as u64,
+            errors: report
+                .errors
+                .into_iter()
+                .map(|e| abi::ReplaceError {
+                    path: e.path,
+                    error: e.error,
+                })
+                .collect(),
+            run_id,
+            aborted: report.aborted,
+        })
+    }
+
+    /// Replaces into a new release of `output_dir`, leaving `target_dir`
+    /// alone. The output is stored like a `CommitUpload` tree and the
+    /// release is named by the hash of that tree, so it is never taken for a
+    /// deploy of the tar of `target_dir`. Rolling `output_dir` back undoes
+    /// the run, so no journal is kept.
+    async fn text_replace_into_release(
+        &self,
+        mut config: text_replace::Setting,
+        output_dir: &str,
+    ) -> Result<text_replace::Report, HubError> {
+        if config.dry_run {
+            return Ok(config.text_replace()?);
+        }
+        let _guard = self.lock_dir(output_dir).await;
+        let staging = self.release_staging(output_dir)?;
+        config.output_path = staging.to_string_lossy().to_string();
+        let stored = config
+            .text_replace()
+            .map_err(HubError::from)
+            .and_then(|report| {
+                if report.aborted {
+                    return Ok((report, None));
+                }
+                let stored = self.objects.add_tree_by_hash(&staging)?;
+                Ok((report, Some(stored)))
+            });
+        let _ = std::fs::remove_dir_all(&staging);
+        let (report, stored) = stored?;
+        if let Some((tree_hash, manifest)) = stored {
+            self.deploy_release(&tree_hash, output_dir, manifest)
+                .await?;
+        }
+        Ok(report)
+    }
+
+    /// Keeps the newest `keep_replace_journals` runs, run ids start with
+    /// the unix time so they sort by age.
+    fn prune_replace_journals(&self) {
+        let Ok(entries) = std::fs::read_dir(self.config.replace_journal_path()) else {
+            return;
+        };
+        let mut runs: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| text_replace::journal::is_journal(p))
+            .collect();
+        runs.sort();
+        let excess = runs.len().saturating_sub(self.config.keep_replace_journals);
+        for path in &runs[..excess] {
+            debug!("Prune replace journal {:?}", path);
+            if let Err(e) = std::fs::remove_dir_all(path) {
+                tracing::warn!("Failed to prune replace journal {:?}: {}", path, e);
+            }
+        }
+    }
+
+    pub fn undo_replace(&self, run_id: &str) -> Result<abi::UndoReplaceData, HubError> {
+        path_is_valid(run_id)?;
+        let dir = self.config.replace_journal_path().join(run_id);
+        if !text_replace::journal::is_journal(&dir) {
+            return Err(HubError::ReplaceRunNotExist(run_id.to_owned()));
+        }
+        let report = text_replace::journal::undo(&dir)?;
+        debug!(
+            "Undo replace run {}: {} restored, {} conflicts",
+            run_id,
+            report.restored.len(),
+            report.conflicts.len()
+        );
+        Ok(abi::UndoReplaceData {
+            restored: report.restored,
+            conflicts: report.conflicts,
+        })
+    }
+
+    pub fn add_tar_dir(&self, tar_hash: &str, item_dir: &str) -> Result<(), HubError> {
+        self.add_tar_dir_entry(tar_hash, item_dir);
+        self.persist()
     }
 
     #[warn(clippy::unwrap_or_default)]
-    pub fn add_tar_dir(&self, tar_hash: &str, item_dir: &str) {
+    fn add_tar_dir_entry(&self, tar_hash: &str, item_dir: &str) {
         let set = self
             .context
             .item_dir_map
@@ -216,109 +717,459 @@ impl MyExtensionHub {
             .or_insert(DashSet::new());
         set.insert(item_dir.to_owned());
     }
-    pub async fn upload_tar(&self, hash: &str, bytes: &[u8]) -> Result<(), HubError> {
-        let Some(_request) = self.context.upload_path_map.get(hash) else {
-            return Err(HubError::ResourceNotFount);
+    pub fn register_tar(&self, tar_hash: &str) -> Result<(), HubError> {
+        if !self.context.tars.contains_key(tar_hash) {
+            let (path, format) = self
+                .tar_file(tar_hash)
+                .ok_or(HubError::TarNotExist(tar_hash.to_owned()))?;
+            let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or_default();
+            let now = unix_now();
+            self.context.tars.insert(
+                tar_hash.to_owned(),
+                TarRecord {
+                    size,
+                    uploaded_at: now,
+                    last_used_at: now,
+                    format,
+                },
+            );
+        }
+        self.persist()
+    }
+
+    /// Receives a tarball from an `UploadTarStream` call, see [`stream_to_file`].
+    /// Like http uploads it may be at most [`MAX_UPLOAD_SIZE`] bytes.
+    pub async fn receive_tar_stream<S>(
+        &self,
+        grant: &Grant,
+        mut stream: S,
+    ) -> Result<abi::UploadTarStreamData, HubError>
+    where
+        S: Stream<Item = Result<abi::UploadChunk, Status>> + Unpin,
+    {
+        let Some(first) = stream
+            .try_next()
+            .await
+            .map_err(|e| HubError::OtherError(e.into()))?
+        else {
+            return Err(HubError::InvalidArgument("empty upload stream".to_owned()));
+        };
+        let abi::UploadChunk {
+            tar_hash,
+            un_tar,
+            data,
+        } = first;
+        if un_tar.is_some() {
+            grant.check(Scope::Deploy)?;
+        }
+        path_is_valid(&tar_hash)?;
+        let stored = self.tar_path(&tar_hash).filter(|path| path.exists());
+        let size = if let Some(target_path) = stored {
+            self.register_tar(&tar_hash)?;
+            tokio::fs::metadata(&target_path).await?.len()
+        } else {
+            let suffix: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(8)
+                .map(char::from)
+                .collect();
+            let tmp_path = self
+                .config
+                .tar_dir_path
+                .join("__tmp__")
+                .join(format!("{}.{}.part", tar_hash, suffix));
+            let first = futures::stream::once(async { Ok(Bytes::from(data)) });
+            let rest = stream
+                .map_ok(|chunk| Bytes::from(chunk.data))
+                .map_err(std::io::Error::other);
+            let received = stream_to_file(&tmp_path, first.chain(rest), MAX_UPLOAD_SIZE).await?;
+            self.install_tar(&tar_hash, &received.path, &received.hash)
+                .await?;
+            received.size
         };
-        if !self.config.tar_dir_path.exists() {
-            std::fs::create_dir_all(&self.config.tar_dir_path)?;
+
+        let target_dir = match un_tar {
+            Some(un_tar) => {
+                self.un_tar_to_dir(
+                    &tar_hash,
+                    &un_tar.target_dir,
+                    un_tar.overwrite.unwrap_or(false),
+                )
+                .await?;
+                Some(un_tar.target_dir)
+            }
+            None => None,
         };
-        let request = _request.clone();
-        let file_name = format!("{}.tar.gz", request.tar_hash);
-        let path = self.config.tar_dir_path.join(&file_name);
-        if !path.exists() {
-            let request = _request.clone();
-            let hasher = blake3::hash(bytes);
-            let hash_str = hasher.to_hex().to_string();
-            if hash_str != request.clone().tar_hash {
-                return Err(HubError::HashNotMatch(request.clone().tar_hash, hash_str));
-            };
-            let mut file = std::fs::File::create(path)?;
-            file.write_all(bytes)?;
+        let format = self
+            .tar_file(&tar_hash)
+            .map(|(_, format)| format)
+            .unwrap_or_default();
+        Ok(abi::UploadTarStreamData {
+            tar_hash,
+            size,
+            target_dir,
+            format: format.to_abi().into(),
+        })
+    }
+
+    /// Moves a fully received temp file into `tar_dir_path` once its hash
+    /// matches and its format is known, splits it into the object store and
+    /// registers the tar.
+    pub async fn install_tar(
+        &self,
+        tar_hash: &str,
+        tmp_path: impl AsRef<Path>,
+        found_hash: &str,
+    ) -> Result<(), HubError> {
+        let tmp_path = tmp_path.as_ref();
+        if found_hash != tar_hash {
+            let _ = tokio::fs::remove_file(tmp_path).await;
+            return Err(HubError::HashNotMatch(
+                tar_hash.to_owned(),
+                found_hash.to_owned(),
+            ));
+        }
+        path_is_valid(tar_hash)?;
+        let format = match ArchiveFormat::detect_file(tmp_path) {
+            Ok(format) => format,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(tmp_path).await;
+                return Err(e);
+            }
         };
-        let request = _request.clone();
+        let file_name = format!("{}{}", tar_hash, format.suffix());
+        let target_path = self.config.tar_dir_path.join(file_name);
+        if target_path.exists() {
+            tokio::fs::remove_file(tmp_path).await?;
+        } else {
+            tokio::fs::rename(tmp_path, &target_path).await?;
+            // an archive that can not be split could never be untarred
+            if let Err(e) = self.tar_manifest(tar_hash).await {
+                let _ = tokio::fs::remove_file(&target_path).await;
+                return Err(e);
+            }
+        }
+        self.register_tar(tar_hash)
+    }
+
+    /// Starts a resumable upload for an url handed out by `UploadTar`. The
+    /// session outlives the url, it expires after
+    /// `upload_session_idle_secs` without any chunk.
+    pub async fn create_upload_session(&self, url: &str) -> Result<String, HubError> {
+        let request = self.verify_upload_url(url)?;
+        self.prune_upload_sessions().await;
+        let session_id: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let tmp_dir = self.config.tar_dir_path.join("__tmp__");
+        tokio::fs::create_dir_all(&tmp_dir).await?;
+        let path = tmp_dir.join(format!("{}.part", session_id));
+        tokio::fs::File::create(&path).await?;
+        self.context.upload_sessions.insert(
+            session_id.clone(),
+            Arc::new(tokio::sync::Mutex::new(UploadSession::new(request, path))),
+        );
+        Ok(session_id)
+    }
+
+    pub fn upload_session(
+        &self,
+        session_id: &str,
+    ) -> Result<Arc<tokio::sync::Mutex<UploadSession>>, HubError> {
         self.context
-            .tar_set
-            .insert(_request.clone().tar_hash.to_owned());
-        let Some(un_tar_request) = request.un_tar else {
+            .upload_sessions
+            .get(session_id)
+            .map(|s| s.clone())
+            .ok_or(HubError::ResourceNotFount)
+    }
+
+    async fn prune_upload_sessions(&self) {
+        let idle = Duration::from_secs(self.config.upload_session_idle_secs);
+        let mut expired = Vec::new();
+        for entry in self.context.upload_sessions.iter() {
+            // A locked session is receiving data right now.
+            if let Ok(session) = entry.value().try_lock() {
+                if session.is_expired(idle) {
+                    expired.push((entry.key().clone(), session.path.clone()));
+                }
+            }
+        }
+        for (session_id, path) in expired {
+            debug!("Upload session {} expired", session_id);
+            self.context.upload_sessions.remove(&session_id);
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+
+    /// Checks the received data against the requested hash, installs the tar
+    /// and untars it when the upload request asked for it. The session is
+    /// closed either way.
+    pub async fn finalize_upload_session(&self, session_id: &str) -> Result<(), HubError> {
+        let session = self.upload_session(session_id)?;
+        let session = session.lock().await;
+        self.context.upload_sessions.remove(session_id);
+        let request = session.request.clone();
+        self.install_tar(&request.tar_hash, &session.path, &session.hash())
+            .await?;
+        let Some(un_tar) = request.un_tar else {
             return Ok(());
         };
-        let request = _request.clone();
         self.un_tar_to_dir(
-            &request.tar_hash.clone(),
-            &un_tar_request.target_dir,
-            un_tar_request.overwrite.unwrap_or(false),
+            &request.tar_hash,
+            &un_tar.target_dir,
+            un_tar.overwrite.unwrap_or(false),
         )
         .await
     }
 
+    /// Installs a tar received by the http upload, then untars it when the
+    /// upload request asked for it.
     pub async fn upload_tar_by_path(
         &self,
-        hash: &str,
-        path: impl AsRef<Path>,
-        target_path: impl AsRef<Path>,
+        request: &abi::UploadTarRequest,
+        received: &ReceivedFile,
     ) -> Result<(), HubError> {
-        let Some(_request) = self.context.upload_path_map.get(hash) else {
-            return Err(HubError::ResourceNotFount);
-        };
-        let target_path = target_path.as_ref();
-        let path = path.as_ref();
-        let bytes = tokio::fs::read(&path).await?;
-        let request = _request.clone();
-        let hasher = blake3::hash(&bytes);
-        let hash_str = hasher.to_hex().to_string();
-        if hash_str != request.clone().tar_hash {
-            return Err(HubError::HashNotMatch(request.clone().tar_hash, hash_str));
+        self.install_tar(&request.tar_hash, &received.path, &received.hash)
+            .await
+            .inspect_err(|e| {
+                debug!("Got error: {:?}, when install file {:?}", e, &received.path)
+            })?;
+        let Some(un_tar_request) = &request.un_tar else {
+            return Ok(());
         };
-        std::fs::rename(&path, target_path).map_err(|e| {
-            debug!(
-                "Got error: {:?}, when move file {:?} to {:?}",
-                e, &path, &target_path
-            );
-            HubError::IOError(e)
-        })?;
-        self.upload_tar(hash, &bytes).await?;
-        Ok(())
+        self.un_tar_to_dir(
+            &request.tar_hash,
+            &un_tar_request.target_dir,
+            un_tar_request.overwrite.unwrap_or(false),
+        )
+        .await
     }
 
-    pub fn download_tar(&self, url: &str) -> Result<(String, Vec<u8>), HubError> {
-        let Some(_request) = self.context.download_path_map.get(url) else {
-            return Err(HubError::ResourceNotFount);
-        };
-        let request = _request.clone();
-        let tar_file_name = format!("{}.tar.gz", request.clone().tar_hash);
-        path_is_valid(&tar_file_name)?;
-        let path = self.config.tar_dir_path.join(tar_file_name);
-        if !path.exists() {
-            return Err(HubError::TarNotExist(request.clone().tar_hash));
-        };
-        let mut file = std::fs::File::open(path)?;
-        let mut bytes = Vec::new();
-        file.read_to_end(&mut bytes)?;
-        Ok((request.clone().tar_hash, bytes))
+    /// Whether `item_dir` is served, from a tar or from a release that has
+    /// none, like an adopted legacy dir.
+    fn dir_is_deployed(&self, item_dir: &str) -> bool {
+        self.context
+            .item_dir_map
+            .iter()
+            .any(|set| set.contains(item_dir))
+            || self
+                .context
+                .releases
+                .get(item_dir)
+                .is_some_and(|history| history.current.is_some())
     }
 
-    pub fn get_download_tar_path(&self, url: &str) -> Result<(String, String), HubError> {
-        let Some(_request) = self.context.download_path_map.get(url) else {
-            return Err(HubError::ResourceNotFount);
-        };
-        let request = _request.clone();
-        let tar_file_name = format!("{}.tar.gz", request.clone().tar_hash);
-        path_is_valid(&tar_file_name)?;
-        let path = self.config.tar_dir_path.join(tar_file_name);
-        if !path.exists() {
-            return Err(HubError::TarNotExist(request.clone().tar_hash));
-        };
-        Ok((request.clone().tar_hash, path.to_string_lossy().to_string()))
+    /// Removes the tarballs that are not deployed to a dir, and the objects
+    /// no remaining tar or release uses. Tars and objects uploaded within the
+    /// lifetime of an upload url are kept, the uploader may not have
+    /// deployed them yet.
+    pub fn clear_unused_tars(&self, dry_run: bool) -> Result<abi::ClearData, HubError> {
+        let mut data = abi::ClearData::default();
+        if !self.config.tar_dir_path.is_dir() {
+            return Ok(data);
+        }
+        for entry in std::fs::read_dir(&self.config.tar_dir_path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let Some((tar_hash, _)) = ArchiveFormat::split_file_name(&file_name) else {
+                continue;
+            };
+            let deployed = self
+                .context
+                .item_dir_map
+                .get(tar_hash)
+                .is_some_and(|set| !set.is_empty());
+            let upload_window = self.config.max_upload_url_ttl_secs;
+            let pending = self
+                .context
+                .tars
+                .get(tar_hash)
+                .is_some_and(|r| r.uploaded_at + upload_window > unix_now());
+            if deployed || pending {
+                continue;
+            }
+            let size = entry.metadata()?.len();
+            if !dry_run {
+                std::fs::remove_file(entry.path())?;
+                self.context.tars.remove(tar_hash);
+                self.context.item_dir_map.remove(tar_hash);
+            }
+            debug!("Clear tar {}, dry run: {}", file_name, dry_run);
+            data.removed.push(file_name);
+            data.freed_bytes += size;
+        }
+        let mut dropped: HashSet<String> = data
+            .removed
+            .iter()
+            .filter_map(|name| ArchiveFormat::split_file_name(name))
+            .map(|(tar_hash, _)| tar_hash.to_owned())
+            .collect();
+        // trees uploaded file by file have no tar, they are kept while a
+        // release of them is
+        for hash in self.objects.manifest_hashes()? {
+            let stored = self.tar_path(&hash).is_some_and(|path| path.exists());
+            let released = self
+                .context
+                .releases
+                .iter()
+                .any(|history| history.releases.iter().any(|r| r.tar_hash == hash));
+            if !stored && !released {
+                dropped.insert(hash);
+            }
+        }
+        let keep_since = unix_now().saturating_sub(self.config.max_upload_url_ttl_secs);
+        let (objects_removed, objects_size) = self.objects.prune(&dropped, keep_since, dry_run)?;
+        data.objects_removed = objects_removed;
+        data.freed_bytes += objects_size;
+        if !dry_run {
+            self.persist()?;
+        }
+        Ok(data)
+    }
+
+    /// Removes `item_dir` from base dir, or every dir nobody deployed to when
+    /// `item_dir` is empty. A deployed dir is only removed with `force`.
+    pub async fn clear_item_dir(
+        &self,
+        item_dir: &str,
+        force: bool,
+        dry_run: bool,
+    ) -> Result<abi::ClearData, HubError> {
+        let mut dirs = Vec::new();
+        if item_dir.is_empty() {
+            if self.config.base_dir.is_dir() {
+                for entry in std::fs::read_dir(&self.config.base_dir)? {
+                    let entry = entry?;
+                    let path = entry.path();
+                    // Deployed dirs are symlinks to their current release.
+                    if !path.is_dir() || self.is_reserved_dir(&path) {
+                        continue;
+                    }
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    if !self.dir_is_deployed(&name) {
+                        dirs.push(name);
+                    }
+                }
+            }
+        } else {
+            path_is_valid(item_dir)?;
+            let path = self.config.base_dir.join(item_dir);
+            if !path.is_dir() || self.is_reserved_dir(&path) {
+                return Err(HubError::DirNotExist(item_dir.to_owned()));
+            }
+            if self.dir_is_deployed(item_dir) && !force {
+                return Err(HubError::DirInUse(item_dir.to_owned()));
+            }
+            dirs.push(item_dir.to_owned());
+        }
+
+        let mut data = abi::ClearData::default();
+        for dir in dirs {
+            let path = self.config.base_dir.join(&dir);
+            let release_dir = self.release_dir(&dir);
+            let mut size = dir_size(&release_dir);
+            if !path.is_symlink() {
+                size += dir_size(&path);
+            }
+            if !dry_run {
+                let _guard = self.lock_dir(&dir).await;
+                // Removes only the link itself when `path` is a symlink.
+                std::fs::remove_dir_all(&path)?;
+                if release_dir.exists() {
+                    std::fs::remove_dir_all(&release_dir)?;
+                }
+                self.context.releases.remove(&dir);
+                for set in self.context.item_dir_map.iter() {
+                    set.remove(&dir);
+                }
+            }
+            debug!("Clear dir {}, dry run: {}", dir, dry_run);
+            data.removed.push(dir);
+            data.freed_bytes += size;
+        }
+        if !dry_run {
+            self.context.item_dir_map.retain(|_, set| !set.is_empty());
+            self.persist()?;
+        }
+        Ok(data)
+    }
+
+    /// The path of a dir a client deploys to, which must be a single name
+    /// and neither one of the hub's own dirs nor a hidden staging dir.
+    pub fn target_dir_path(&self, dir: &str) -> Result<PathBuf, HubError> {
+        path_is_valid(dir)?;
+        let path = self.config.base_dir.join(dir);
+        if dir.starts_with('.') || self.is_reserved_dir(&path) {
+            return Err(HubError::InvalidPath(dir.to_owned()));
+        }
+        Ok(path)
+    }
+
+    /// Dirs below base dir that hold the hub's own data.
+    pub fn is_reserved_dir(&self, path: &Path) -> bool {
+        self.config.tar_dir_path.starts_with(path)
+            || self.config.meta_path().starts_with(path)
+            || self.config.releases_path().starts_with(path)
+            || self.config.replace_journal_path().starts_with(path)
+            || self.config.objects_path().starts_with(path)
+    }
+
+    /// The tar a download url names. Only checks the url, see
+    /// [`MyExtensionHub::count_download`].
+    pub fn get_download_tar_path(
+        &self,
+        url: &str,
+    ) -> Result<(String, String, ArchiveFormat), HubError> {
+        let request = self.check_download_url(url)?;
+        let (path, format) = self
+            .tar_file(&request.tar_hash)
+            .filter(|(path, _)| path.exists())
+            .ok_or(HubError::TarNotExist(request.clone().tar_hash))?;
+        Ok((
+            request.clone().tar_hash,
+            path.to_string_lossy().to_string(),
+            format,
+        ))
+    }
+
+    /// Counts a use of a download url, once the tar is about to be sent.
+    pub fn count_download(&self, url: &str) -> Result<(), HubError> {
+        let request = self.use_download_url(url)?;
+        self.touch_tar(&request.tar_hash);
+        self.persist()
     }
 }
 
 #[tonic::async_trait]
 impl ExtensionHub for MyExtensionHub {
+    async fn get_server_info(
+        &self,
+        request: Request<abi::GetServerInfoRequest>,
+    ) -> Result<Response<abi::GetServerInfoResponse>, Status> {
+        authenticated(&request)?;
+        let mut formats = ArchiveFormat::ALL.to_vec();
+        // smaller than gzip and quick to unpack
+        formats.sort_by_key(|format| *format != ArchiveFormat::TarZst);
+        Ok(abi::GetServerInfoResponse::success_response(Some(
+            abi::ServerInfo {
+                archive_formats: formats
+                    .into_iter()
+                    .map(|format| format.to_abi().into())
+                    .collect(),
+            },
+        )))
+    }
+
     async fn check_tar(
         &self,
         request: Request<abi::CheckTarRequest>,
     ) -> Result<Response<abi::CheckTarResponse>, Status> {
+        require(&request, Scope::Upload)?;
         let abi::CheckTarRequest {
             tar_hash,
             file_path,
@@ -337,22 +1188,46 @@ impl ExtensionHub for MyExtensionHub {
         &self,
         request: Request<abi::UploadTarRequest>,
     ) -> Result<Response<abi::UploadTarResponse>, Status> {
+        let grant = require(&request, Scope::Upload)?;
         let request = request.into_inner();
-        let reply = self.generate_upload_url(request)?;
+        if request.un_tar.is_some() {
+            grant.check(Scope::Deploy)?;
+        }
+        let (upload_url, expires_at) = self.generate_upload_url(request)?;
         Ok(abi::UploadTarResponse::success_response(Some(
-            abi::UploadTarData { upload_url: reply },
+            abi::UploadTarData {
+                upload_url,
+                expires_at,
+            },
         )))
     }
 
+    async fn upload_tar_stream(
+        &self,
+        request: Request<Streaming<abi::UploadChunk>>,
+    ) -> Result<Response<abi::UploadTarStreamResponse>, Status> {
+        let grant = require(&request, Scope::Upload)?;
+        let stream = request.into_inner();
+        let reply = self.receive_tar_stream(&grant, stream).await?;
+        Ok(abi::UploadTarStreamResponse::success_response(Some(reply)))
+    }
+
     async fn download_tar(
         &self,
         request: Request<abi::DownloadTarRequest>,
     ) -> Result<Response<abi::DownloadTarResponse>, Status> {
+        require(&request, Scope::Read)?;
         let request = request.into_inner();
-        let reply = self.generate_download_url(request)?;
+        let format = self
+            .tar_file(&request.tar_hash)
+            .map(|(_, format)| format)
+            .unwrap_or_default();
+        let (download_url, expires_at) = self.generate_download_url(request)?;
         Ok(abi::DownloadTarResponse::success_response(Some(
             abi::DownloadTarData {
-                download_url: reply,
+                download_url,
+                expires_at,
+                format: format.to_abi().into(),
             },
         )))
     }
@@ -361,6 +1236,7 @@ impl ExtensionHub for MyExtensionHub {
         &self,
         request: Request<abi::UnTarRequest>,
     ) -> Result<Response<abi::UnTarResponse>, Status> {
+        require(&request, Scope::Deploy)?;
         let abi::UnTarRequest {
             tar_hash,
             target_dir,
@@ -375,31 +1251,160 @@ impl ExtensionHub for MyExtensionHub {
         }
     }
 
+    async fn plan_upload(
+        &self,
+        request: Request<abi::PlanUploadRequest>,
+    ) -> Result<Response<abi::PlanUploadResponse>, Status> {
+        require(&request, Scope::Upload)?;
+        let data = self.plan_upload(request.into_inner().files)?;
+        Ok(abi::PlanUploadResponse::success_response(Some(data)))
+    }
+
+    async fn upload_objects(
+        &self,
+        request: Request<Streaming<abi::ObjectChunk>>,
+    ) -> Result<Response<abi::UploadObjectsResponse>, Status> {
+        require(&request, Scope::Upload)?;
+        let data = self.receive_objects(request.into_inner()).await?;
+        Ok(abi::UploadObjectsResponse::success_response(Some(data)))
+    }
+
+    async fn commit_upload(
+        &self,
+        request: Request<abi::CommitUploadRequest>,
+    ) -> Result<Response<abi::CommitUploadResponse>, Status> {
+        require(&request, Scope::Deploy)?;
+        let release = self.commit_upload(request.into_inner()).await?;
+        Ok(abi::CommitUploadResponse::success_response(Some(release)))
+    }
+
     async fn replace_text(
         &self,
         request: Request<abi::ReplaceTextRequest>,
     ) -> Result<Response<abi::ReplaceTextResponse>, Status> {
+        require(&request, Scope::Deploy)?;
         let request = request.into_inner();
-        let reply = self.text_replace_by_request(request);
+        let reply = self.text_replace_by_request(request).await;
         match reply {
-            Ok(_) => Ok(abi::ReplaceTextResponse::success_response()),
+            Ok(data) => Ok(abi::ReplaceTextResponse::success_response(Some(data))),
             Err(e) => Err(e.into()),
         }
     }
 
+    async fn undo_replace(
+        &self,
+        request: Request<abi::UndoReplaceRequest>,
+    ) -> Result<Response<abi::UndoReplaceResponse>, Status> {
+        require(&request, Scope::Deploy)?;
+        let abi::UndoReplaceRequest { run_id } = request.into_inner();
+        let data = self.undo_replace(&run_id)?;
+        Ok(abi::UndoReplaceResponse::success_response(Some(data)))
+    }
+
+    async fn list_releases(
+        &self,
+        request: Request<abi::ListReleasesRequest>,
+    ) -> Result<Response<abi::ListReleasesResponse>, Status> {
+        require(&request, Scope::Read)?;
+        let abi::ListReleasesRequest { target_dir } = request.into_inner();
+        let releases = self.list_releases(&target_dir)?;
+        Ok(abi::ListReleasesResponse::success_response(Some(
+            abi::ListReleasesData { releases },
+        )))
+    }
+
+    async fn rollback(
+        &self,
+        request: Request<abi::RollbackRequest>,
+    ) -> Result<Response<abi::RollbackResponse>, Status> {
+        require(&request, Scope::Deploy)?;
+        let abi::RollbackRequest {
+            target_dir,
+            tar_hash,
+        } = request.into_inner();
+        self.target_dir_path(&target_dir)?;
+        let _guard = self.lock_dir(&target_dir).await;
+        let release = self.rollback_release(&target_dir, &tar_hash)?;
+        Ok(abi::RollbackResponse::success_response(Some(
+            release.to_abi(true),
+        )))
+    }
+
+    async fn list_extensions(
+        &self,
+        request: Request<abi::ListExtensionsRequest>,
+    ) -> Result<Response<abi::ListExtensionsResponse>, Status> {
+        require(&request, Scope::Read)?;
+        let extensions = self.list_extensions();
+        Ok(abi::ListExtensionsResponse::success_response(Some(
+            abi::ListExtensionsData { extensions },
+        )))
+    }
+
+    async fn get_extension(
+        &self,
+        request: Request<abi::GetExtensionRequest>,
+    ) -> Result<Response<abi::GetExtensionResponse>, Status> {
+        require(&request, Scope::Read)?;
+        let abi::GetExtensionRequest { target_dir } = request.into_inner();
+        let extension = self.get_extension(&target_dir)?;
+        Ok(abi::GetExtensionResponse::success_response(Some(extension)))
+    }
+
+    async fn list_tars(
+        &self,
+        request: Request<abi::ListTarsRequest>,
+    ) -> Result<Response<abi::ListTarsResponse>, Status> {
+        require(&request, Scope::Read)?;
+        let tars = self.list_tars();
+        Ok(abi::ListTarsResponse::success_response(Some(
+            abi::ListTarsData { tars },
+        )))
+    }
+
+    async fn get_tar_info(
+        &self,
+        request: Request<abi::GetTarInfoRequest>,
+    ) -> Result<Response<abi::GetTarInfoResponse>, Status> {
+        require(&request, Scope::Read)?;
+        let abi::GetTarInfoRequest { tar_hash } = request.into_inner();
+        let info = self.get_tar_info(&tar_hash)?;
+        Ok(abi::GetTarInfoResponse::success_response(Some(info)))
+    }
+
+    async fn delete_tar(
+        &self,
+        request: Request<abi::DeleteTarRequest>,
+    ) -> Result<Response<abi::DeleteTarResponse>, Status> {
+        require(&request, Scope::Admin)?;
+        let abi::DeleteTarRequest { tar_hash } = request.into_inner();
+        let reply = self.delete_tar(&tar_hash)?;
+        Ok(abi::DeleteTarResponse::success_response(Some(reply)))
+    }
+
     async fn clear_tar_dir(
         &self,
-        _request: Request<abi::ClearTarDirRequest>,
+        request: Request<abi::ClearTarDirRequest>,
     ) -> Result<Response<abi::ClearTarDirResponse>, Status> {
-        let reply = abi::ClearTarDirResponse {};
-        Ok(Response::new(reply))
+        require(&request, Scope::Admin)?;
+        let abi::ClearTarDirRequest { dry_run } = request.into_inner();
+        let reply = self.clear_unused_tars(dry_run.unwrap_or(false))?;
+        Ok(abi::ClearTarDirResponse::success_response(Some(reply)))
     }
 
     async fn clear_dir(
         &self,
-        _request: Request<abi::ClearDirRequest>,
+        request: Request<abi::ClearDirRequest>,
     ) -> Result<Response<abi::ClearDirResponse>, Status> {
-        let reply = abi::ClearDirResponse {};
-        Ok(Response::new(reply))
+        require(&request, Scope::Admin)?;
+        let abi::ClearDirRequest {
+            dir,
+            force,
+            dry_run,
+        } = request.into_inner();
+        let reply = self
+            .clear_item_dir(&dir, force.unwrap_or(false), dry_run.unwrap_or(false))
+            .await?;
+        Ok(abi::ClearDirResponse::success_response(Some(reply)))
     }
 }
@@ -0,0 +1,165 @@
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tokio::time::Duration;
+
+use extension_hub::abi::extension_hub as abi;
+use extension_hub::error::HubError;
+
+use crate::store::unix_now;
+
+extern crate extension_hub;
+
+/// Lifetimes used when a request does not ask for one.
+pub const UPLOAD_URL_TTL: Duration = Duration::from_secs(30);
+pub const DOWNLOAD_URL_TTL: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UrlOperation {
+    Upload,
+    Download,
+}
+
+/// Everything the server needs to serve a url, so no state is kept between
+/// handing it out and using it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UrlClaims {
+    pub op: UrlOperation,
+    pub tar_hash: String,
+    /// Unix seconds
+    pub expires_at: u64,
+    /// Untar into this dir once uploaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overwrite: Option<bool>,
+    /// Number of times the url may be used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_uses: Option<u32>,
+}
+
+/// How often a url with `max_uses` has been used, kept until it expires.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UrlUses {
+    pub uses: u32,
+    pub expires_at: u64,
+}
+
+impl UrlClaims {
+    pub fn upload(request: &abi::UploadTarRequest, ttl: Duration) -> Self {
+        UrlClaims {
+            op: UrlOperation::Upload,
+            tar_hash: request.tar_hash.clone(),
+            expires_at: unix_now() + ttl.as_secs(),
+            target_dir: request.un_tar.as_ref().map(|u| u.target_dir.clone()),
+            overwrite: request.un_tar.as_ref().and_then(|u| u.overwrite),
+            max_uses: None,
+        }
+    }
+
+    pub fn download(request: &abi::DownloadTarRequest, ttl: Duration) -> Self {
+        UrlClaims {
+            op: UrlOperation::Download,
+            tar_hash: request.tar_hash.clone(),
+            expires_at: unix_now() + ttl.as_secs(),
+            target_dir: None,
+            overwrite: None,
+            max_uses: request.max_uses,
+        }
+    }
+
+    pub fn upload_request(&self) -> abi::UploadTarRequest {
+        abi::UploadTarRequest {
+            tar_hash: self.tar_hash.clone(),
+            un_tar: self
+                .target_dir
+                .as_ref()
+                .map(|target_dir| abi::UnTarRequest {
+                    tar_hash: self.tar_hash.clone(),
+                    target_dir: target_dir.clone(),
+                    overwrite: self.overwrite,
+                }),
+            ttl_secs: None,
+        }
+    }
+}
+
+/// Signs urls as `<hex claims>.<hex mac>`, the mac is a blake3 keyed hash
+/// with a key derived from the configured secret.
+pub struct UrlSigner {
+    key: [u8; 32],
+}
+
+impl fmt::Debug for UrlSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UrlSigner").finish_non_exhaustive()
+    }
+}
+
+impl UrlSigner {
+    pub fn new(secret: &str) -> Self {
+        UrlSigner {
+            key: blake3::derive_key("extension-hub signed url v1", secret.as_bytes()),
+        }
+    }
+
+    /// A signer whose urls stop working when the server restarts.
+    pub fn random() -> Self {
+        let mut key = [0u8; 32];
+        thread_rng().fill_bytes(&mut key);
+        UrlSigner { key }
+    }
+
+    pub fn sign(&self, claims: &UrlClaims) -> Result<String, HubError> {
+        let payload =
+            serde_json::to_vec(claims).map_err(|e| HubError::OtherError(anyhow::anyhow!(e)))?;
+        let mac = blake3::keyed_hash(&self.key, &payload);
+        Ok(format!("{}.{}", to_hex(&payload), mac.to_hex()))
+    }
+
+    pub fn verify(&self, url: &str, op: UrlOperation) -> Result<UrlClaims, HubError> {
+        let invalid = |reason: &str| HubError::InvalidUrl(reason.to_owned());
+        let (payload, mac) = url.split_once('.').ok_or_else(|| invalid("malformed"))?;
+        let payload = from_hex(payload).ok_or_else(|| invalid("malformed"))?;
+        let mac = blake3::Hash::from_hex(mac).map_err(|_| invalid("malformed"))?;
+        // `Hash` equality is constant time.
+        if blake3::keyed_hash(&self.key, &payload) != mac {
+            return Err(invalid("bad signature"));
+        }
+        let claims: UrlClaims =
+            serde_json::from_slice(&payload).map_err(|_| invalid("malformed"))?;
+        if claims.op != op {
+            return Err(invalid("wrong operation"));
+        }
+        if claims.expires_at < unix_now() {
+            return Err(invalid("expired"));
+        }
+        Ok(claims)
+    }
+}
+
+/// The part of a signed url that identifies it.
+pub fn url_id(url: &str) -> &str {
+    url.rsplit_once('.').map(|(_, mac)| mac).unwrap_or(url)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The requested lifetime, or `default`, capped at `max_secs`.
+pub fn url_ttl(requested_secs: Option<u64>, default: Duration, max_secs: u64) -> Duration {
+    let secs = requested_secs.unwrap_or(default.as_secs());
+    Duration::from_secs(secs.min(max_secs))
+}
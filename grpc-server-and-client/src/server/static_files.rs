@@ -1,17 +1,257 @@
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 
-use axum::{handler::HandlerWithoutStateExt, http::StatusCode, Router};
-use tower_http::services::ServeDir;
+use axum::{
+    extract::{Request, State},
+    handler::Handler,
+    http::{header::CACHE_CONTROL, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use clap::Parser;
+use globset::{Glob, GlobMatcher, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+use tower::{ServiceBuilder, ServiceExt};
+use tower_http::compression::CompressionLayer;
+use tower_http::services::{ServeDir, ServeFile};
 
+use extension_hub::error::HubError;
+
+use crate::auth::{normalize_path, protect_static, Authenticator};
 use crate::server::MyExtensionHub;
 
-pub fn wrap_files_router(state: Arc<MyExtensionHub>, router: Router) -> Router {
-    async fn handle_404() -> (StatusCode, &'static str) {
-        (StatusCode::NOT_FOUND, "Not found")
+extern crate extension_hub;
+
+/// `Cache-Control` of pages, they are checked on every load.
+const HTML_CACHE_CONTROL: &str = "no-cache";
+/// `Cache-Control` of files whose name carries a content hash.
+const HASHED_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheRule {
+    /// Glob of static file paths
+    pub path: String,
+    pub cache_control: String,
+}
+
+/// Parses `<glob>=<cache-control>`, as given on the command line.
+impl FromStr for CacheRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, cache_control) = s
+            .split_once('=')
+            .ok_or_else(|| "expected <glob>=<cache-control>".to_owned())?;
+        Ok(CacheRule {
+            path: path.trim().to_owned(),
+            cache_control: cache_control.trim().to_owned(),
+        })
+    }
+}
+
+#[derive(Parser, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct StaticConfig {
+    /// `Cache-Control` of static files as `<glob>=<cache-control>`, the first
+    /// matching rule wins. Without a match `index.html` gets `no-cache` and
+    /// file names carrying a hex content hash, like `app.3f9a2c1d.js`, are
+    /// cached for a year
+    #[arg(long = "cache-rule")]
+    #[serde(default)]
+    pub cache_rules: Vec<CacheRule>,
+    /// Glob of target dirs that answer paths without a file extension with
+    /// their `index.html`, for apps routing on the client
+    #[arg(long = "spa-dir")]
+    #[serde(default)]
+    pub spa_dirs: Vec<String>,
+    /// Do not compress static files on the fly, precompressed `.gz` and `.br`
+    /// files are still served
+    #[arg(long)]
+    #[serde(default)]
+    pub no_compression: bool,
+}
+
+/// What `wrap_files_router` needs to answer a request besides `ServeDir`.
+#[derive(Debug)]
+struct StaticFiles {
+    base_dir: PathBuf,
+    cache_rules: Vec<(GlobMatcher, HeaderValue)>,
+    spa_dirs: GlobSet,
+}
+
+impl StaticFiles {
+    fn new(base_dir: PathBuf, config: &StaticConfig) -> Result<Self, HubError> {
+        let cache_rules = config
+            .cache_rules
+            .iter()
+            .map(|rule| {
+                let glob = Glob::new(rule.path.trim_start_matches('/'))
+                    .map_err(|e| HubError::ConfigureError(e.to_string()))?;
+                let value = HeaderValue::from_str(&rule.cache_control)
+                    .map_err(|e| HubError::ConfigureError(e.to_string()))?;
+                Ok((glob.compile_matcher(), value))
+            })
+            .collect::<Result<_, HubError>>()?;
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &config.spa_dirs {
+            let glob = Glob::new(pattern.trim_matches('/'))
+                .map_err(|e| HubError::ConfigureError(e.to_string()))?;
+            builder.add(glob);
+        }
+        Ok(StaticFiles {
+            base_dir,
+            cache_rules,
+            spa_dirs: builder
+                .build()
+                .map_err(|e| HubError::ConfigureError(e.to_string()))?,
+        })
+    }
+
+    /// `path` is relative to `base_dir` and already normalized.
+    fn cache_control(&self, path: &str) -> Option<HeaderValue> {
+        if let Some((_, value)) = self
+            .cache_rules
+            .iter()
+            .find(|(glob, _)| glob.is_match(path))
+        {
+            return Some(value.clone());
+        }
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        if file_name == "index.html" {
+            Some(HeaderValue::from_static(HTML_CACHE_CONTROL))
+        } else if is_hashed(file_name) {
+            Some(HeaderValue::from_static(HASHED_CACHE_CONTROL))
+        } else {
+            None
+        }
+    }
+
+    /// The `index.html` answering a client-side route, `path` being what
+    /// `ServeDir` found nothing for.
+    fn spa_index(&self, path: &str) -> Option<(String, PathBuf)> {
+        let path = normalize_path(path);
+        let parts = path.split('/').collect::<Vec<_>>();
+        let dir = parts.first().filter(|dir| !dir.is_empty())?;
+        let last = parts.last()?;
+        if parts.contains(&"..") || last.contains('.') || !self.spa_dirs.is_match(dir) {
+            return None;
+        }
+        let index = self.base_dir.join(dir).join("index.html");
+        index
+            .is_file()
+            .then(|| (format!("{}/index.html", dir), index))
+    }
+}
+
+/// Whether a dot or dash separated part of the name, before its extension,
+/// is a hex content hash of 8 or more digits with at least one letter, so
+/// dates and versions like `chunk-20240101.js` do not count. Bundlers hashing
+/// in base64, like Vite, need a `cache_rules` entry, names such as
+/// `icon-facebook2.svg` must not be cached for a year.
+fn is_hashed(file_name: &str) -> bool {
+    let Some((stem, _)) = file_name.rsplit_once('.') else {
+        return false;
+    };
+    stem.split(['.', '-']).skip(1).any(|part| {
+        part.len() >= 8
+            && part
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+            && part.bytes().any(|b| (b'a'..=b'f').contains(&b))
+    })
+}
+
+async fn handle_404() -> (StatusCode, &'static str) {
+    (StatusCode::NOT_FOUND, "Not found")
+}
+
+/// Fallback of `ServeDir`, serves the `index.html` of a SPA dir for paths
+/// that are routes of the app rather than files.
+async fn spa_fallback(State(files): State<Arc<StaticFiles>>, request: Request) -> Response {
+    let Some((index_path, index)) = files.spa_index(request.uri().path()) else {
+        return handle_404().await.into_response();
+    };
+    let mut response = match ServeFile::new(index)
+        .precompressed_br()
+        .precompressed_gzip()
+        .oneshot(request)
+        .await
+    {
+        Ok(response) => response,
+        Err(never) => match never {},
+    };
+    if let Some(value) = files.cache_control(&index_path) {
+        response.headers_mut().insert(CACHE_CONTROL, value);
     }
+    response.into_response()
+}
+
+/// Axum middleware answering 404 for the hub's own dirs under base dir and
+/// for hidden staging dirs, which would otherwise serve releases, journals and
+/// the tar registry past `protected_paths`.
+async fn hide_internal_dirs(
+    State(state): State<Arc<MyExtensionHub>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = normalize_path(request.uri().path());
+    let dir = path.split('/').next().unwrap_or_default();
+    if dir.starts_with('.')
+        || (!dir.is_empty() && state.is_reserved_dir(&state.config.base_dir.join(dir)))
+    {
+        return handle_404().await.into_response();
+    }
+    next.run(request).await
+}
+
+/// Axum middleware adding `Cache-Control` to the files `ServeDir` found.
+async fn set_cache_control(
+    State(files): State<Arc<StaticFiles>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut path = normalize_path(request.uri().path());
+    if request.uri().path().ends_with('/') {
+        path = format!("{}/index.html", path)
+            .trim_start_matches('/')
+            .to_owned();
+    }
+    let mut response = next.run(request).await;
+    let served = response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED;
+    if served && !response.headers().contains_key(CACHE_CONTROL) {
+        if let Some(value) = files.cache_control(&path) {
+            response.headers_mut().insert(CACHE_CONTROL, value);
+        }
+    }
+    response
+}
 
-    let service = handle_404.into_service();
+pub fn wrap_files_router(
+    state: Arc<MyExtensionHub>,
+    auth: Arc<Authenticator>,
+    router: Router,
+) -> Result<Router, HubError> {
+    let config = &state.config.static_files;
+    let files = Arc::new(StaticFiles::new(state.config.base_dir.clone(), config)?);
 
-    let server_dir = ServeDir::new(&state.config.base_dir).not_found_service(service);
-    router.fallback_service(server_dir)
+    let server_dir = ServeDir::new(&state.config.base_dir)
+        .precompressed_br()
+        .precompressed_gzip()
+        .fallback(spa_fallback.with_state(files.clone()));
+    Ok(router.fallback_service(
+        ServiceBuilder::new()
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                hide_internal_dirs,
+            ))
+            .layer(middleware::from_fn_with_state(auth, protect_static))
+            .layer(
+                CompressionLayer::new()
+                    .br(!config.no_compression)
+                    .gzip(!config.no_compression),
+            )
+            .layer(middleware::from_fn_with_state(files, set_cache_control))
+            .service(server_dir),
+    ))
 }
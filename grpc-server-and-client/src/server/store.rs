@@ -0,0 +1,110 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use extension_hub::error::HubError;
+
+use crate::inventory::TarRecord;
+use crate::release::ReleaseHistory;
+use crate::signed_url::UrlUses;
+
+extern crate extension_hub;
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Snapshot of the tar registry and the deployment map, as written to disk.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(default, deserialize_with = "deserialize_tars")]
+    pub tars: BTreeMap<String, TarRecord>,
+    #[serde(default)]
+    pub dirs: BTreeMap<String, BTreeSet<String>>,
+    #[serde(default)]
+    pub releases: BTreeMap<String, ReleaseHistory>,
+    /// Uses of download urls with a use limit
+    #[serde(default)]
+    pub url_uses: BTreeMap<String, UrlUses>,
+}
+
+/// Accepts the plain list of hashes written before tars had records.
+fn deserialize_tars<'de, D>(deserializer: D) -> Result<BTreeMap<String, TarRecord>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Tars {
+        Hashes(BTreeSet<String>),
+        Records(BTreeMap<String, TarRecord>),
+    }
+    Ok(match Tars::deserialize(deserializer)? {
+        Tars::Hashes(hashes) => hashes
+            .into_iter()
+            .map(|hash| (hash, TarRecord::default()))
+            .collect(),
+        Tars::Records(records) => records,
+    })
+}
+
+/// Manifest file kept next to the tarballs.
+///
+/// Every save writes a temp file and renames it over the previous manifest,
+/// so a crash leaves either the old or the new snapshot, never a torn one.
+#[derive(Debug)]
+pub struct MetaStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl MetaStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        MetaStore {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn load(&self) -> Result<Manifest, HubError> {
+        if !self.path.exists() {
+            return Ok(Manifest::default());
+        }
+        let bytes = std::fs::read(&self.path)?;
+        serde_json::from_slice(&bytes).map_err(|e| {
+            HubError::ConfigureError(format!(
+                "invalid manifest {}: {}",
+                self.path.to_string_lossy(),
+                e
+            ))
+        })
+    }
+
+    /// Builds the snapshot while holding the store lock, so concurrent saves
+    /// can not overwrite a newer snapshot with an older one.
+    pub fn save_with(&self, snapshot: impl FnOnce() -> Manifest) -> Result<(), HubError> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let manifest = snapshot();
+        if let Some(parent) = self.path.parent() {
+            if !parent.exists() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        let bytes = serde_json::to_vec_pretty(&manifest)
+            .map_err(|e| HubError::OtherError(anyhow::anyhow!(e)))?;
+        let tmp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
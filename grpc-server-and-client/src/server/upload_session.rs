@@ -0,0 +1,92 @@
+use bytes::Bytes;
+use futures::{Stream, TryStreamExt};
+use std::path::PathBuf;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{Duration, Instant};
+
+use extension_hub::abi::extension_hub as abi;
+use extension_hub::error::HubError;
+
+use crate::file::MAX_UPLOAD_SIZE;
+
+extern crate extension_hub;
+
+/// A resumable upload. Chunks must arrive in order, so the digest is updated
+/// as they are written and finalizing never has to read the file back.
+#[derive(Debug)]
+pub struct UploadSession {
+    pub request: abi::UploadTarRequest,
+    pub path: PathBuf,
+    pub offset: u64,
+    hasher: blake3::Hasher,
+    updated_at: Instant,
+}
+
+impl UploadSession {
+    pub fn new(request: abi::UploadTarRequest, path: impl Into<PathBuf>) -> Self {
+        UploadSession {
+            request,
+            path: path.into(),
+            offset: 0,
+            hasher: blake3::Hasher::new(),
+            updated_at: Instant::now(),
+        }
+    }
+
+    pub fn is_expired(&self, idle: Duration) -> bool {
+        self.updated_at.elapsed() > idle
+    }
+
+    pub fn hash(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+
+    /// Appends `stream` at `offset`, which must be the number of bytes already
+    /// received. A broken stream keeps everything received so far, so the
+    /// client can query the offset and continue from there. A session may grow
+    /// to at most [`MAX_UPLOAD_SIZE`] bytes, like a single upload.
+    pub async fn append<S>(&mut self, offset: u64, stream: S) -> Result<u64, HubError>
+    where
+        S: Stream<Item = Result<Bytes, std::io::Error>>,
+    {
+        if offset != self.offset {
+            return Err(HubError::OffsetNotMatch(self.offset, offset));
+        }
+        self.updated_at = Instant::now();
+        let mut file = OpenOptions::new().append(true).open(&self.path).await?;
+        futures::pin_mut!(stream);
+
+        let mut received = async {
+            while let Some(bytes) = stream.try_next().await? {
+                if self.offset + bytes.len() as u64 > MAX_UPLOAD_SIZE {
+                    return Err(HubError::UploadTooLarge(MAX_UPLOAD_SIZE));
+                }
+                file.write_all(&bytes).await?;
+                self.hasher.update(&bytes);
+                self.offset += bytes.len() as u64;
+            }
+            Ok::<(), HubError>(())
+        }
+        .await;
+        if received.is_ok() {
+            received = file.flush().await.map_err(HubError::from);
+        }
+        if let Err(e) = received {
+            // Drop whatever was written past the last hashed byte.
+            let _ = file.flush().await;
+            file.set_len(self.offset).await?;
+            if let HubError::UploadTooLarge(_) = e {
+                return Err(e);
+            }
+            tracing::debug!(
+                "Upload session {:?} interrupted at {}: {:?}",
+                self.path,
+                self.offset,
+                e
+            );
+        }
+        self.updated_at = Instant::now();
+        Ok(self.offset)
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::STAGING_PREFIX;
+
+const JOURNAL_FILE: &str = "journal.json";
+const ORIGINALS_DIR: &str = "files";
+
+/// What a replacement run overwrote, so it can be undone.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Journal {
+    pub output_path: String,
+    pub entries: Vec<JournalEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Relative to `output_path`
+    pub path: String,
+    /// File name of the saved original under `files/`, `None` when the run
+    /// created the file
+    #[serde(default)]
+    pub original: Option<String>,
+    /// blake3 of the content the run wrote
+    pub written_hash: String,
+}
+
+/// Saves originals into `<dir>/files` before they are overwritten, the
+/// journal itself is written by `finish`.
+#[derive(Debug)]
+pub struct JournalWriter {
+    dir: PathBuf,
+    journal: Journal,
+}
+
+impl JournalWriter {
+    pub fn create(dir: impl Into<PathBuf>, output_path: &str) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(ORIGINALS_DIR))?;
+        Ok(JournalWriter {
+            dir,
+            journal: Journal {
+                output_path: output_path.to_owned(),
+                entries: Vec::new(),
+            },
+        })
+    }
+
+    /// Call before `output` is overwritten with content hashing to
+    /// `written_hash`.
+    pub fn record(
+        &mut self,
+        relative: &str,
+        output: &Path,
+        written_hash: &blake3::Hash,
+    ) -> Result<()> {
+        let original = if output.exists() {
+            let name = self.journal.entries.len().to_string();
+            fs::copy(output, self.dir.join(ORIGINALS_DIR).join(&name))
+                .with_context(|| format!("failed to save original of {}", relative))?;
+            Some(name)
+        } else {
+            None
+        };
+        self.journal.entries.push(JournalEntry {
+            path: relative.to_owned(),
+            original,
+            written_hash: written_hash.to_hex().to_string(),
+        });
+        Ok(())
+    }
+
+    /// Removes the dir, for runs that wrote nothing in the end.
+    pub fn discard(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+
+    /// Writes the journal, or removes the dir when nothing was recorded.
+    /// Returns whether a journal was kept.
+    pub fn finish(self) -> Result<bool> {
+        if self.journal.entries.is_empty() {
+            fs::remove_dir_all(&self.dir)?;
+            return Ok(false);
+        }
+        write_journal(&self.dir, &self.journal)?;
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct UndoReport {
+    pub restored: Vec<String>,
+    /// Files changed since the run, left as they are
+    pub conflicts: Vec<String>,
+}
+
+pub fn is_journal(dir: &Path) -> bool {
+    dir.join(JOURNAL_FILE).is_file()
+}
+
+/// Restores every file of the journal in `dir` that still holds what the
+/// run wrote. Only the conflicting entries are kept, so the run can be
+/// undone again once they are fixed, the journal is removed when none are
+/// left.
+pub fn undo(dir: &Path) -> Result<UndoReport> {
+    let bytes = fs::read(dir.join(JOURNAL_FILE))?;
+    let mut journal: Journal = serde_json::from_slice(&bytes)?;
+    let output_path = PathBuf::from(&journal.output_path);
+    let mut report = UndoReport::default();
+    let mut remaining = Vec::new();
+    for entry in journal.entries.into_iter().rev() {
+        let path = output_path.join(&entry.path);
+        let unchanged = file_hash(&path)
+            .map(|hash| hash.to_hex().as_str() == entry.written_hash)
+            .unwrap_or(false);
+        if !unchanged {
+            report.conflicts.push(entry.path.clone());
+            remaining.push(entry);
+            continue;
+        }
+        match &entry.original {
+            Some(name) => {
+                restore(&dir.join(ORIGINALS_DIR).join(name), &path)
+                    .with_context(|| format!("failed to restore {}", entry.path))?;
+            }
+            None => fs::remove_file(&path)?,
+        }
+        report.restored.push(entry.path);
+    }
+    if remaining.is_empty() {
+        fs::remove_dir_all(dir)?;
+    } else {
+        remaining.reverse();
+        journal.entries = remaining;
+        write_journal(dir, &journal)?;
+    }
+    Ok(report)
+}
+
+fn file_hash(path: &Path) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Puts `original` in place of `path` through a temp file and a rename, so
+/// files hard linked to `path`, in other releases or the object store, keep
+/// their content.
+fn restore(original: &Path, path: &Path) -> Result<()> {
+    let parent = path.parent().context("output has no parent dir")?;
+    let tmp = tempfile::Builder::new()
+        .prefix(STAGING_PREFIX)
+        .suffix(".tmp")
+        .tempfile_in(parent)?
+        .into_temp_path();
+    fs::copy(original, &tmp)?;
+    tmp.persist(path)?;
+    Ok(())
+}
+
+fn write_journal(dir: &Path, journal: &Journal) -> Result<()> {
+    let bytes = serde_json::to_vec_pretty(journal)?;
+    let tmp_path = dir.join(format!("{}.tmp", JOURNAL_FILE));
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(&tmp_path, dir.join(JOURNAL_FILE))?;
+    Ok(())
+}
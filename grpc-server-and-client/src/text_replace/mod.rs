@@ -1,80 +1,590 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+};
 
-use anyhow::{bail, Result};
-use globset::Glob;
+use anyhow::{bail, Context, Result};
+use encoding_rs::Encoding;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use regex::bytes::{Captures, Regex};
+use similar::TextDiff;
+
+pub mod journal;
+pub mod stream;
+
+use journal::JournalWriter;
+use stream::{HashWriter, Outcome};
+use tempfile::TempPath;
+
+/// Longest diff excerpt kept per file, in bytes.
+const DIFF_EXCERPT_LEN: usize = 4096;
+/// Dry runs only diff files up to this size, in bytes.
+const DIFF_SOURCE_LEN: u64 = 1024 * 1024;
+/// Longest match of a regex rule, longer ones may be missed where a file is
+/// split into chunks.
+const MAX_REGEX_MATCH_LEN: usize = 64 * 1024;
+/// Prefix of the temp files written next to outputs, the walk skips them.
+const STAGING_PREFIX: &str = ".replace-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RuleMode {
+    /// Replaces every occurrence of the pattern
+    #[default]
+    Literal,
+    /// The pattern is a regex, the replacement may refer to capture groups
+    /// as `$1` or `${name}`
+    Regex,
+    /// Like `Literal`, but only where the match is not part of a longer word
+    WholeWord,
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub pattern: String,
+    pub replacement: String,
+    pub mode: RuleMode,
+}
+
+impl Rule {
+    pub fn literal(pattern: impl Into<String>, replacement: impl Into<String>) -> Self {
+        Rule {
+            pattern: pattern.into(),
+            replacement: replacement.into(),
+            mode: RuleMode::Literal,
+        }
+    }
+
+    pub fn compile(&self) -> Result<CompiledRule> {
+        if self.pattern.is_empty() {
+            bail!("empty pattern");
+        }
+        let escaped = regex::escape(&self.pattern);
+        let (regex, max_match_len) = match self.mode {
+            RuleMode::Regex => (
+                Regex::new(&self.pattern)
+                    .with_context(|| format!("invalid regex: {}", self.pattern))?,
+                MAX_REGEX_MATCH_LEN,
+            ),
+            RuleMode::Literal => (Regex::new(&escaped)?, self.pattern.len()),
+            RuleMode::WholeWord => (
+                Regex::new(&format!(r"\b{{start-half}}(?:{})\b{{end-half}}", escaped))?,
+                self.pattern.len(),
+            ),
+        };
+        Ok(CompiledRule {
+            regex,
+            replacement: self.replacement.clone(),
+            mode: self.mode,
+            max_match_len,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct CompiledRule {
+    regex: Regex,
+    replacement: String,
+    mode: RuleMode,
+    /// Longest match the streaming replacer waits for
+    max_match_len: usize,
+}
+
+impl CompiledRule {
+    fn expand(&self, caps: &Captures, out: &mut Vec<u8>) {
+        match self.mode {
+            RuleMode::Regex => caps.expand(self.replacement.as_bytes(), out),
+            RuleMode::Literal | RuleMode::WholeWord => {
+                out.extend_from_slice(self.replacement.as_bytes())
+            }
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Setting {
-    pub old_web_prefix: String,
-    pub new_web_prefix: String,
+    /// Applied in order, each to the output of the previous one
+    pub rules: Vec<Rule>,
+    /// Globs of paths relative to `source_path`, matched files are rewritten
+    /// whatever their suffix
+    pub include_path: Option<Vec<String>>,
+    /// Globs of paths relative to `source_path`, a matched dir is skipped
+    /// with everything below it
     pub exclude_path: Option<Vec<String>>,
     pub source_path: String,
     pub output_path: String,
     pub file_types: Option<Vec<String>>,
+    /// Computes the changes without writing anything
+    pub dry_run: bool,
+    /// Dir to keep the originals of overwritten files in, see [`journal`]
+    pub journal_path: Option<PathBuf>,
+    /// Encoding of the files, UTF-8 files are matched as bytes so invalid
+    /// sequences are kept as they are
+    pub encoding: &'static Encoding,
+    /// Keeps the permissions and mtime of rewritten files
+    pub keep_metadata: bool,
+    /// Stages every rewritten file and only moves them into place when all
+    /// succeeded, otherwise no rewritten file is written
+    pub transactional: bool,
 }
 
 impl Setting {
-    pub fn text_replace(&self) -> Result<()> {
+    pub fn text_replace(&self) -> Result<Report> {
         map_files(self)
     }
 }
 
-pub fn map_files(setting: &Setting) -> Result<()> {
+/// What a `map_files` run did. Files that fail are recorded in `errors`
+/// and do not stop the run.
+///
+/// When `output_path` is not `source_path`, the files left as they are,
+/// excluded ones included, get hard linked, or copied, there too, so it holds
+/// the whole tree.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub files_scanned: usize,
+    /// Files matching neither the file types nor the include globs
+    pub skipped_by_type: usize,
+    /// Files and dirs matching the exclude globs
+    pub skipped_by_exclusion: usize,
+    /// Files that look binary, they are never rewritten
+    pub skipped_binary: usize,
+    pub files_changed: usize,
+    pub replacements: usize,
+    pub changes: Vec<FileChange>,
+    pub errors: Vec<FileError>,
+    /// Whether a journal was written to `journal_path`
+    pub journaled: bool,
+    /// A transactional run failed, none of `changes` were written
+    pub aborted: bool,
+}
+
+impl Report {
+    fn add_error(&mut self, path: String, error: anyhow::Error) {
+        tracing::warn!("Failed to replace text in {}: {:#}", path, error);
+        self.errors.push(FileError {
+            path,
+            error: format!("{:#}", error),
+        });
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileError {
+    pub path: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileChange {
+    /// Relative to `source_path`
+    pub path: String,
+    pub replacements: usize,
+    /// Unified diff excerpt, only computed for dry runs
+    pub diff: String,
+}
+
+fn diff_excerpt(path: &str, old: &str, new: &str) -> String {
+    let mut diff = TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(2)
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string();
+    if diff.len() > DIFF_EXCERPT_LEN {
+        let mut end = DIFF_EXCERPT_LEN;
+        while !diff.is_char_boundary(end) {
+            end -= 1;
+        }
+        diff.truncate(end);
+        diff.push_str("\n... diff truncated\n");
+    }
+    diff
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob =
+            Glob::new(pattern).with_context(|| format!("invalid glob pattern: {}", pattern))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+pub fn map_files(setting: &Setting) -> Result<Report> {
     let Setting {
-        source_path,
-        file_types,
+        rules,
+        include_path,
         exclude_path,
-        old_web_prefix,
-        new_web_prefix,
+        source_path,
         output_path,
+        file_types,
+        dry_run,
+        journal_path,
+        encoding,
+        keep_metadata,
+        transactional,
     } = setting;
     let file_types: Vec<String> = file_types.to_owned().unwrap_or(vec![]);
+    let include = glob_set(include_path.as_deref().unwrap_or_default())?;
     let mut exclude_path = exclude_path.to_owned().unwrap_or(vec![]);
-    exclude_path.push("\\.git$".to_owned());
+    exclude_path.push(".git".to_owned());
+    exclude_path.push("**/.git".to_owned());
+    let exclude = glob_set(&exclude_path)?;
+    let rules = rules
+        .iter()
+        .map(Rule::compile)
+        .collect::<Result<Vec<_>>>()?;
     let path = PathBuf::from(&source_path);
     if !path.exists() {
         bail!("dir not exists");
     }
-    let walker = walkdir::WalkDir::new(source_path)
-        .into_iter()
-        .filter_entry(|e| {
-            let relative_path = e.path().strip_prefix(source_path).unwrap();
-            !exclude_path.iter().any(|p| {
-                let glob = Glob::new(p)
-                    .unwrap_or_else(|_| panic!("invalid glob pattern: {}", p))
-                    .compile_matcher();
-                glob.is_match(relative_path.to_str().unwrap_or_default())
-            })
-        });
-    for entry in walker {
-        let entry = entry?;
+    let mut journal = match journal_path {
+        Some(dir) if !dry_run => Some(JournalWriter::create(dir, output_path)?),
+        _ => None,
+    };
+    let options = ReplaceOptions {
+        rules: &rules,
+        encoding,
+        dry_run: *dry_run,
+        keep_metadata: *keep_metadata,
+    };
+    let mut report = Report::default();
+    let mut staged = Vec::new();
+    let mut skipped_by_exclusion = 0;
+    let copy_unchanged = !dry_run && Path::new(source_path) != Path::new(output_path);
+    // the excluded dir being walked, only entered when copying to the output
+    let mut excluded_dir: Option<PathBuf> = None;
+    let mut walker = walkdir::WalkDir::new(source_path).into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let path = e.path().map(|p| p.to_string_lossy().to_string());
+                report.add_error(path.unwrap_or_default(), e.into());
+                continue;
+            }
+        };
         let path = entry.path();
-        let is_file = path.is_file();
-        if !is_file {
+        let relative_path = path.strip_prefix(source_path)?;
+        let relative = relative_path.to_string_lossy().to_string();
+        let output = PathBuf::from(&output_path).join(relative_path);
+        let excluded = match &excluded_dir {
+            Some(dir) if path.starts_with(dir) => true,
+            _ if exclude.is_match(relative_path) => {
+                skipped_by_exclusion += 1;
+                if path.is_dir() {
+                    excluded_dir = Some(path.to_owned());
+                }
+                true
+            }
+            _ => false,
+        };
+        if excluded {
+            // excluded files are never rewritten, but a new release still
+            // holds them unchanged
+            if !copy_unchanged {
+                if entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+            let copied = if path.is_dir() {
+                fs::create_dir_all(&output).map_err(Into::into)
+            } else if path.is_file() {
+                link_or_copy(path, &output)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = copied {
+                report.add_error(relative, e);
+            }
+            continue;
+        }
+        if path.is_dir() {
+            if copy_unchanged {
+                if let Err(e) = fs::create_dir_all(&output) {
+                    report.add_error(relative, e.into());
+                }
+            }
             continue;
         }
+        let is_staging = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(STAGING_PREFIX));
+        if !path.is_file() || is_staging {
+            continue;
+        }
+        report.files_scanned += 1;
         let file_type = path
             .extension()
             .unwrap_or_default()
             .to_str()
             .unwrap_or_default()
             .to_string();
-        if !file_types.contains(&file_type) {
-            continue;
+        let unchanged = if !file_types.contains(&file_type) && !include.is_match(relative_path) {
+            report.skipped_by_type += 1;
+            true
+        } else {
+            match replace_file(&options, path, &output, &relative) {
+                Ok(FileOutcome::Binary) => {
+                    report.skipped_binary += 1;
+                    true
+                }
+                Ok(FileOutcome::Unchanged) => true,
+                Ok(FileOutcome::Changed(change, file)) => {
+                    let committed = match file {
+                        Some(file) if *transactional => {
+                            staged.push(file);
+                            Ok(())
+                        }
+                        Some(file) => file.commit(journal.as_mut()),
+                        None => Ok(()),
+                    };
+                    match committed {
+                        Ok(()) => {
+                            report.files_changed += 1;
+                            report.replacements += change.replacements;
+                            report.changes.push(change);
+                            false
+                        }
+                        Err(e) => {
+                            report.add_error(relative.clone(), e);
+                            true
+                        }
+                    }
+                }
+                Err(e) => {
+                    report.add_error(relative.clone(), e);
+                    true
+                }
+            }
+        };
+        if unchanged && copy_unchanged {
+            if let Err(e) = link_or_copy(path, &output) {
+                report.add_error(relative, e);
+            }
         }
-        let content = fs::read_to_string(path)?;
-        if !content.contains(old_web_prefix) {
-            continue;
+    }
+    report.skipped_by_exclusion = skipped_by_exclusion;
+    if *transactional {
+        if report.errors.is_empty() {
+            if let Err((path, e)) = commit_all(staged, journal.as_mut()) {
+                report.add_error(path, e);
+            }
         }
-        let new_content = content.replace(old_web_prefix, new_web_prefix);
-        let path = path.strip_prefix(source_path)?;
-        let out_dir = PathBuf::from(&output_path);
-        let path = out_dir.join(path);
-        let parent = path.parent().unwrap();
-        if !parent.exists() {
-            fs::create_dir_all(parent)?;
+        report.aborted = !report.errors.is_empty();
+    }
+    if let Some(journal) = journal {
+        if report.aborted {
+            journal.discard()?;
+        } else {
+            report.journaled = journal.finish()?;
+        }
+    }
+    tracing::info!(
+        "Replaced text in {}: {} files scanned, {} skipped by type, {} skipped by exclusion, {} skipped as binary, {} changed, {} replacements, {} errors, dry run: {}, aborted: {}",
+        source_path,
+        report.files_scanned,
+        report.skipped_by_type,
+        report.skipped_by_exclusion,
+        report.skipped_binary,
+        report.files_changed,
+        report.replacements,
+        report.errors.len(),
+        dry_run,
+        report.aborted
+    );
+    Ok(report)
+}
+
+struct ReplaceOptions<'a> {
+    rules: &'a [CompiledRule],
+    encoding: &'static Encoding,
+    dry_run: bool,
+    keep_metadata: bool,
+}
+
+enum FileOutcome {
+    Binary,
+    Unchanged,
+    /// The rewritten file is staged unless it is a dry run
+    Changed(FileChange, Option<StagedFile>),
+}
+
+/// A rewritten file in a temp file next to its output, removed when dropped.
+struct StagedFile {
+    tmp: TempPath,
+    output: PathBuf,
+    relative: String,
+    written_hash: blake3::Hash,
+}
+
+impl StagedFile {
+    fn commit(self, journal: Option<&mut JournalWriter>) -> Result<()> {
+        if let Some(journal) = journal {
+            journal.record(&self.relative, &self.output, &self.written_hash)?;
+        }
+        self.tmp.persist(&self.output)?;
+        Ok(())
+    }
+
+    /// Moves the file into place, keeping what it replaced as a backup that
+    /// is removed when dropped.
+    fn swap_in(self) -> Result<(PathBuf, Option<TempPath>)> {
+        let backup = if self.output.exists() {
+            let parent = self.output.parent().context("output has no parent dir")?;
+            let backup = tempfile::Builder::new()
+                .prefix(STAGING_PREFIX)
+                .suffix(".orig")
+                .tempfile_in(parent)?
+                .into_temp_path();
+            fs::rename(&self.output, &backup)?;
+            Some(backup)
+        } else {
+            None
+        };
+        if let Err(e) = self.tmp.persist(&self.output) {
+            if let Some(backup) = &backup {
+                fs::rename(backup, &self.output)?;
+            }
+            return Err(e.into());
+        }
+        Ok((self.output, backup))
+    }
+}
+
+/// Moves all staged files into place, or puts back the ones already moved
+/// when one fails.
+fn commit_all(
+    staged: Vec<StagedFile>,
+    journal: Option<&mut JournalWriter>,
+) -> Result<(), (String, anyhow::Error)> {
+    if let Some(journal) = journal {
+        for file in &staged {
+            journal
+                .record(&file.relative, &file.output, &file.written_hash)
+                .map_err(|e| (file.relative.clone(), e))?;
+        }
+    }
+    let mut committed = Vec::new();
+    for file in staged {
+        let relative = file.relative.clone();
+        match file.swap_in() {
+            Ok(swapped) => committed.push(swapped),
+            Err(e) => {
+                for (output, backup) in committed.into_iter().rev() {
+                    let restored = match backup {
+                        Some(backup) => fs::rename(&backup, &output),
+                        None => fs::remove_file(&output),
+                    };
+                    if let Err(e) = restored {
+                        tracing::error!("Failed to restore {:?}: {}", output, e);
+                    }
+                }
+                return Err((relative, e));
+            }
         }
-        fs::write(path, new_content)?;
     }
     Ok(())
 }
+
+/// Hard links `path` to `output`, or copies it where linking is not
+/// possible, e.g. across file systems.
+fn link_or_copy(path: &Path, output: &Path) -> Result<()> {
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::hard_link(path, output).is_err() {
+        fs::copy(path, output)?;
+    }
+    Ok(())
+}
+
+/// Applies the rules to one file. A first pass only counts the
+/// replacements, changed files are rewritten by a second one into a temp
+/// file next to `output`.
+fn replace_file(
+    options: &ReplaceOptions,
+    path: &Path,
+    output: &Path,
+    relative: &str,
+) -> Result<FileOutcome> {
+    let ReplaceOptions {
+        rules,
+        encoding,
+        dry_run,
+        keep_metadata,
+    } = *options;
+    let metadata = fs::metadata(path)?;
+    let diff_source = dry_run && metadata.len() <= DIFF_SOURCE_LEN;
+    let mut replaced_content = Vec::new();
+    let outcome = if diff_source {
+        stream::replace(
+            rules,
+            encoding,
+            &mut File::open(path)?,
+            &mut replaced_content,
+        )?
+    } else {
+        stream::replace(rules, encoding, &mut File::open(path)?, &mut io::sink())?
+    };
+    let replaced = match outcome {
+        Outcome::Binary => return Ok(FileOutcome::Binary),
+        Outcome::Replaced(0) => return Ok(FileOutcome::Unchanged),
+        Outcome::Replaced(count) => count,
+    };
+    if dry_run {
+        let diff = if diff_source {
+            let content = fs::read(path)?;
+            let (old, _) = encoding.decode_without_bom_handling(&content);
+            let (new, _) = encoding.decode_without_bom_handling(&replaced_content);
+            diff_excerpt(relative, &old, &new)
+        } else {
+            format!("... file larger than {} bytes, no diff\n", DIFF_SOURCE_LEN)
+        };
+        return Ok(FileOutcome::Changed(
+            FileChange {
+                path: relative.to_owned(),
+                replacements: replaced,
+                diff,
+            },
+            None,
+        ));
+    }
+    let parent = output.parent().context("output has no parent dir")?;
+    if !parent.exists() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = tempfile::Builder::new()
+        .prefix(STAGING_PREFIX)
+        .suffix(".tmp")
+        .tempfile_in(parent)?;
+    let mut writer = HashWriter::new(BufWriter::new(tmp.as_file()));
+    let Outcome::Replaced(replaced) =
+        stream::replace(rules, encoding, &mut File::open(path)?, &mut writer)?
+    else {
+        return Ok(FileOutcome::Binary);
+    };
+    let (_, written_hash) = writer.finish();
+    if keep_metadata {
+        tmp.as_file().set_permissions(metadata.permissions())?;
+        tmp.as_file().set_modified(metadata.modified()?)?;
+    }
+    // so a crash after the rename can not leave an empty file behind
+    tmp.as_file().sync_all()?;
+    Ok(FileOutcome::Changed(
+        FileChange {
+            path: relative.to_owned(),
+            replacements: replaced,
+            diff: String::new(),
+        },
+        Some(StagedFile {
+            tmp: tmp.into_temp_path(),
+            output: output.to_owned(),
+            relative: relative.to_owned(),
+            written_hash,
+        }),
+    ))
+}
@@ -0,0 +1,410 @@
+use std::io::{self, Read, Write};
+
+use anyhow::{bail, Result};
+use encoding_rs::{
+    Decoder, DecoderResult, Encoder, EncoderResult, Encoding, UTF_16BE, UTF_16LE, UTF_8,
+};
+
+use super::CompiledRule;
+
+/// Bytes read from a file at a time.
+const CHUNK_LEN: usize = 64 * 1024;
+/// A file with a NUL byte in its first `BINARY_SNIFF_LEN` bytes is binary.
+const BINARY_SNIFF_LEN: usize = 8 * 1024;
+/// Bytes kept around the part of a buffer being matched, enough for one
+/// UTF-8 char, so `\b` and anchors see what is next to it.
+const CONTEXT_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Nothing was written
+    Binary,
+    /// Number of replacements
+    Replaced(usize),
+}
+
+pub fn is_binary(head: &[u8]) -> bool {
+    head[..head.len().min(BINARY_SNIFF_LEN)].contains(&0)
+}
+
+fn is_utf16(encoding: &'static Encoding) -> bool {
+    encoding == UTF_16LE || encoding == UTF_16BE
+}
+
+/// Applies `rules` to what `reader` yields and writes the result to
+/// `writer`, holding a few chunks in memory at most. Files in `encoding`
+/// are decoded to UTF-8 for matching and encoded back when written.
+pub fn replace(
+    rules: &[CompiledRule],
+    encoding: &'static Encoding,
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+) -> Result<Outcome> {
+    let mut stages: Vec<Stage> = rules.iter().map(Stage::new).collect();
+    let mut decoder = (encoding != UTF_8).then(|| encoding.new_decoder_without_bom_handling());
+    let mut encoder = TextEncoder::new(encoding);
+    let mut buf = vec![0; CHUNK_LEN];
+    let mut first = true;
+    loop {
+        let len = read_full(reader, &mut buf)?;
+        let last = len < buf.len();
+        let chunk = &buf[..len];
+        if first && !is_utf16(encoding) && is_binary(chunk) {
+            return Ok(Outcome::Binary);
+        }
+        first = false;
+        let mut data = match &mut decoder {
+            Some(decoder) => decode(decoder, chunk, last)?,
+            None => chunk.to_vec(),
+        };
+        for stage in &mut stages {
+            data = stage.feed(&data, last);
+        }
+        writer.write_all(&encoder.encode(&data, last)?)?;
+        if last {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(Outcome::Replaced(stages.iter().map(|s| s.count).sum()))
+}
+
+/// Reads until `buf` is full, less only at the end of the input.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+fn decode(decoder: &mut Decoder, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+    let capacity = decoder
+        .max_utf8_buffer_length_without_replacement(chunk.len())
+        .unwrap_or(chunk.len() * 3);
+    let mut text = String::with_capacity(capacity);
+    let (result, _) = decoder.decode_to_string_without_replacement(chunk, &mut text, last);
+    match result {
+        DecoderResult::InputEmpty => Ok(text.into_bytes()),
+        DecoderResult::Malformed(_, _) => {
+            bail!("not valid {}", decoder.encoding().name())
+        }
+        DecoderResult::OutputFull => bail!("decode buffer too small"),
+    }
+}
+
+/// One rule of the chain. Input is buffered until no match starting in it
+/// could still grow, matches may be at most `max_match_len` bytes long.
+struct Stage<'a> {
+    rule: &'a CompiledRule,
+    pending: Vec<u8>,
+    /// Bytes at the front of `pending` that were already handled
+    context: usize,
+    count: usize,
+}
+
+impl<'a> Stage<'a> {
+    fn new(rule: &'a CompiledRule) -> Self {
+        Stage {
+            rule,
+            pending: Vec::new(),
+            context: 0,
+            count: 0,
+        }
+    }
+
+    fn feed(&mut self, data: &[u8], last: bool) -> Vec<u8> {
+        self.pending.extend_from_slice(data);
+        let len = self.pending.len();
+        let safe = if last {
+            len
+        } else {
+            len.saturating_sub(self.rule.max_match_len + CONTEXT_LEN)
+                .max(self.context)
+        };
+        let mut out = Vec::with_capacity(len);
+        let mut emitted = self.context;
+        let mut cut = safe;
+        let mut at = self.context;
+        let mut last_end = None;
+        while at <= len {
+            let Some(caps) = self.rule.regex.captures_at(&self.pending, at) else {
+                break;
+            };
+            let Some(m) = caps.get(0) else {
+                break;
+            };
+            if !last && (m.start() >= safe || m.end() + CONTEXT_LEN > len) {
+                cut = m.start().min(safe);
+                break;
+            }
+            if m.is_empty() && last_end == Some(m.end()) {
+                at = m.end() + 1;
+                continue;
+            }
+            out.extend_from_slice(&self.pending[emitted..m.start()]);
+            self.rule.expand(&caps, &mut out);
+            emitted = m.end();
+            last_end = Some(m.end());
+            self.count += 1;
+            at = if m.is_empty() { m.end() + 1 } else { m.end() };
+        }
+        let cut = cut.max(emitted);
+        out.extend_from_slice(&self.pending[emitted..cut]);
+        let keep = cut.saturating_sub(CONTEXT_LEN);
+        self.pending.drain(..keep);
+        self.context = cut - keep;
+        out
+    }
+}
+
+/// Encodes UTF-8 chunks that may split a char.
+struct TextEncoder {
+    encoding: &'static Encoding,
+    encoder: Encoder,
+    carry: Vec<u8>,
+}
+
+impl TextEncoder {
+    fn new(encoding: &'static Encoding) -> Self {
+        TextEncoder {
+            encoding,
+            encoder: encoding.new_encoder(),
+            carry: Vec::new(),
+        }
+    }
+
+    fn encode(&mut self, data: &[u8], last: bool) -> Result<Vec<u8>> {
+        if self.encoding == UTF_8 {
+            return Ok(data.to_vec());
+        }
+        self.carry.extend_from_slice(data);
+        let valid = match std::str::from_utf8(&self.carry) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() && !last => e.valid_up_to(),
+            Err(_) => bail!("replaced text is not valid UTF-8"),
+        };
+        let text = std::str::from_utf8(&self.carry[..valid])?;
+        let out = if is_utf16(self.encoding) {
+            // encoding_rs only decodes UTF-16
+            let big_endian = self.encoding == UTF_16BE;
+            text.encode_utf16()
+                .flat_map(|unit| {
+                    if big_endian {
+                        unit.to_be_bytes()
+                    } else {
+                        unit.to_le_bytes()
+                    }
+                })
+                .collect()
+        } else {
+            let capacity = self
+                .encoder
+                .max_buffer_length_from_utf8_without_replacement(text.len())
+                .unwrap_or(text.len() * 4);
+            let mut out = Vec::with_capacity(capacity);
+            let (result, _) = self
+                .encoder
+                .encode_from_utf8_to_vec_without_replacement(text, &mut out, last);
+            match result {
+                EncoderResult::InputEmpty => {}
+                EncoderResult::Unmappable(c) => {
+                    bail!("'{}' can not be encoded as {}", c, self.encoding.name())
+                }
+                EncoderResult::OutputFull => bail!("encode buffer too small"),
+            }
+            out
+        };
+        self.carry.drain(..valid);
+        Ok(out)
+    }
+}
+
+/// Passes writes through, hashing them for the journal.
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W) -> Self {
+        HashWriter {
+            inner,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    pub fn finish(self) -> (W, blake3::Hash) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_replace::{Rule, RuleMode};
+
+    fn rule(pattern: &str, replacement: &str, mode: RuleMode) -> CompiledRule {
+        Rule {
+            pattern: pattern.to_owned(),
+            replacement: replacement.to_owned(),
+            mode,
+        }
+        .compile()
+        .unwrap()
+    }
+
+    /// Feeds `input` in pieces of `piece` bytes.
+    fn feed_in_pieces(rule: &CompiledRule, input: &[u8], piece: usize) -> (Vec<u8>, usize) {
+        let mut stage = Stage::new(rule);
+        let mut out = Vec::new();
+        let mut pieces = input.chunks(piece).peekable();
+        while let Some(data) = pieces.next() {
+            out.extend(stage.feed(data, pieces.peek().is_none()));
+        }
+        if input.is_empty() {
+            out.extend(stage.feed(&[], true));
+        }
+        (out, stage.count)
+    }
+
+    /// Every way of splitting `input` must give what one piece gives.
+    fn assert_split_invariant(rule: &CompiledRule, input: &str, expected: &str) {
+        for piece in 1..=input.len().max(1) {
+            let (out, _) = feed_in_pieces(rule, input.as_bytes(), piece);
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                expected,
+                "pieces of {} bytes",
+                piece
+            );
+        }
+    }
+
+    #[test]
+    fn literal_across_pieces() {
+        let rule = rule("OLD", "NEW", RuleMode::Literal);
+        assert_split_invariant(&rule, "OLD xOLDx OLDOLD", "NEW xNEWx NEWNEW");
+        assert_split_invariant(&rule, "OL D", "OL D");
+    }
+
+    #[test]
+    fn literal_replacement_is_not_expanded() {
+        let rule = rule("a", "$0", RuleMode::Literal);
+        assert_split_invariant(&rule, "bab", "b$0b");
+    }
+
+    #[test]
+    fn whole_word_sees_context_after_drain() {
+        let rule = rule("foo", "bar", RuleMode::WholeWord);
+        assert_split_invariant(
+            &rule,
+            "xfoo foo foox _foo foo_ (foo) foo",
+            "xfoo bar foox _foo foo_ (bar) bar",
+        );
+    }
+
+    #[test]
+    fn whole_word_next_to_multibyte_letter() {
+        let rule = rule("foo", "bar", RuleMode::WholeWord);
+        assert_split_invariant(&rule, "éfoo foo fooé", "éfoo bar fooé");
+    }
+
+    #[test]
+    fn anchors_after_drain() {
+        let rule = rule("(?m)^a", "b", RuleMode::Regex);
+        let input = "a\n".repeat(40_000) + "xa";
+        let expected = "b\n".repeat(40_000) + "xa";
+        for piece in [1_000, 4_096, 65_536, 70_000] {
+            let (out, count) = feed_in_pieces(&rule, input.as_bytes(), piece);
+            assert_eq!(String::from_utf8(out).unwrap(), expected);
+            assert_eq!(count, 40_000);
+        }
+    }
+
+    #[test]
+    fn end_anchor_only_at_the_end() {
+        let rule = rule("a$", "b", RuleMode::Regex);
+        assert_split_invariant(&rule, "aaaa", "aaab");
+    }
+
+    #[test]
+    fn empty_matches_follow_replace_all() {
+        let rule = rule("x*", "-", RuleMode::Regex);
+        for input in ["axxb", "", "xx", "abc"] {
+            let expected = rule.regex.replace_all(input.as_bytes(), b"-".as_slice());
+            assert_split_invariant(&rule, input, std::str::from_utf8(&expected).unwrap());
+        }
+    }
+
+    #[test]
+    fn capture_groups() {
+        let rule = rule(r"/api/v(\d+)", "/gw/api/v$1", RuleMode::Regex);
+        assert_split_invariant(&rule, "GET /api/v12/x", "GET /gw/api/v12/x");
+    }
+
+    #[test]
+    fn match_across_the_chunk_boundary() {
+        let rules = [rule("OLD", "NEW", RuleMode::Literal)];
+        for offset in 0..=3 {
+            let mut input = vec![b'x'; CHUNK_LEN - offset];
+            input.extend_from_slice(b"OLD");
+            input.extend(vec![b'y'; CHUNK_LEN]);
+            let mut out = Vec::new();
+            let outcome = replace(&rules, UTF_8, &mut input.as_slice(), &mut out).unwrap();
+            assert_eq!(outcome, Outcome::Replaced(1));
+            let mut expected = vec![b'x'; CHUNK_LEN - offset];
+            expected.extend_from_slice(b"NEW");
+            expected.extend(vec![b'y'; CHUNK_LEN]);
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn chained_rules_see_previous_output() {
+        let rules = [
+            rule("a", "b", RuleMode::Literal),
+            rule("bb", "c", RuleMode::Literal),
+        ];
+        let mut out = Vec::new();
+        let outcome = replace(&rules, UTF_8, &mut b"ab".as_slice(), &mut out).unwrap();
+        assert_eq!(out, b"c");
+        assert_eq!(outcome, Outcome::Replaced(2));
+    }
+
+    #[test]
+    fn binary_files_are_left_alone() {
+        let rules = [rule("a", "b", RuleMode::Literal)];
+        let mut out = Vec::new();
+        let outcome = replace(&rules, UTF_8, &mut b"a\0a".as_slice(), &mut out).unwrap();
+        assert_eq!(outcome, Outcome::Binary);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn utf16_round_trip() {
+        let rules = [rule("é", "e", RuleMode::Literal)];
+        let input: Vec<u8> = "café".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let mut out = Vec::new();
+        replace(&rules, UTF_16LE, &mut input.as_slice(), &mut out).unwrap();
+        let expected: Vec<u8> = "cafe".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        assert_eq!(out, expected);
+    }
+}